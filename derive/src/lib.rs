@@ -0,0 +1,226 @@
+//! `#[derive(RubyWrap)]` for the `ruby_wrap_data` crate.
+//!
+//! Don't depend on this crate directly; enable the `derive` feature of
+//! `ruby_wrap_data` and use the re-exported macro from there.
+//!
+//! ```rust,ignore
+//! #[derive(Default, RubyWrap)]
+//! #[ruby(class = "Geometry::Point", free_immediately)]
+//! struct Point {
+//!     x: f64,
+//!     y: f64,
+//! }
+//!
+//! // later, from your extension's Init function
+//! let klass = Point::define_class();
+//! ```
+//!
+//! The derive generates the type's `DataType` descriptor and `TypedData`
+//! impl, plus a `RubyWrap` impl whose `define_class` defines the Ruby class
+//! with an alloc function wrapping `Point::default()`.
+//!
+//! ## Attributes
+//!
+//! * `class = "..."` - the Ruby class name, including any enclosing modules;
+//!   defaults to the name of the Rust type
+//! * `mark` - mark the Ruby values the type holds (see `DataType::mark`)
+//! * `compact` - mark them as movable instead (see `DataType::compact`)
+//! * `memsize` - report `MemSize::memsize` to Ruby (see `DataType::memsize`)
+//! * `free_immediately` - drop the data during GC sweep rather than
+//!   deferring it (see `DataType::free_immediately`)
+//! * `drop_in_background` - drop the data on a background thread (see
+//!   `DataType::drop_in_background`)
+//! * `embeddable` - keep the data inside the Ruby object where it fits (see
+//!   `DataType::embeddable`; needs the `embed` feature)
+//! * `clone` - let Ruby copy instances by cloning the data (see
+//!   `define_clone`); without it, `dup` and `clone` raise a `TypeError`
+//! * `inspect` - define `inspect` from the type's `Debug` implementation
+//!   (see `define_inspect`)
+//! * `to_s` - define `to_s` from the type's `Display` implementation (see
+//!   `define_to_s`)
+//! * `eq` - define `==` from the type's `PartialEq` implementation (see
+//!   `define_eq`)
+//! * `hash` - define `eql?` and `hash` from the type's `Eq` and `Hash`
+//!   implementations (see `define_hash`)
+//! * `cmp` - define `<=>` from the type's `PartialOrd` implementation and
+//!   include `Comparable` (see `define_cmp`)
+//! * `each` - define `each` from the type's `IntoIterator` implementation for
+//!   references and include `Enumerable` (see `define_each`)
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::{DeriveInput, LitStr};
+
+#[proc_macro_derive(RubyWrap, attributes(ruby))]
+pub fn derive_ruby_wrap(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    class: Option<String>,
+    mark: bool,
+    compact: bool,
+    memsize: bool,
+    free_immediately: bool,
+    drop_in_background: bool,
+    embeddable: bool,
+    clone: bool,
+    inspect: bool,
+    to_s: bool,
+    eq: bool,
+    hash: bool,
+    cmp: bool,
+    each: bool,
+}
+
+fn parse_options(input: &DeriveInput) -> syn::Result<Options> {
+    let mut options = Options::default();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("ruby"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("class") {
+                let class: LitStr = meta.value()?.parse()?;
+                options.class = Some(class.value());
+            } else if meta.path.is_ident("mark") {
+                options.mark = true;
+            } else if meta.path.is_ident("compact") {
+                options.compact = true;
+            } else if meta.path.is_ident("memsize") {
+                options.memsize = true;
+            } else if meta.path.is_ident("free_immediately") {
+                options.free_immediately = true;
+            } else if meta.path.is_ident("drop_in_background") {
+                options.drop_in_background = true;
+            } else if meta.path.is_ident("embeddable") {
+                options.embeddable = true;
+            } else if meta.path.is_ident("clone") {
+                options.clone = true;
+            } else if meta.path.is_ident("inspect") {
+                options.inspect = true;
+            } else if meta.path.is_ident("to_s") {
+                options.to_s = true;
+            } else if meta.path.is_ident("eq") {
+                options.eq = true;
+            } else if meta.path.is_ident("hash") {
+                options.hash = true;
+            } else if meta.path.is_ident("cmp") {
+                options.cmp = true;
+            } else if meta.path.is_ident("each") {
+                options.each = true;
+            } else {
+                return Err(meta.error("unsupported ruby attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    if options.mark && options.compact {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "`mark` and `compact` cannot be used together; `compact` marks too",
+        ));
+    }
+    Ok(options)
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        // the data type descriptor is a static, and statics can't be generic
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "RubyWrap cannot be derived for generic types",
+        ));
+    }
+
+    let options = parse_options(input)?;
+    let ident = &input.ident;
+    let class = options.class.unwrap_or_else(|| ident.to_string());
+    let name = LitStr::new(&format!("{}\0", class), ident.span());
+
+    let mut data_type = quote! { ::ruby_wrap_data::DataType::new(#name) };
+    if options.mark {
+        data_type = quote! { #data_type.mark() };
+    }
+    if options.compact {
+        data_type = quote! { #data_type.compact() };
+    }
+    if options.memsize {
+        data_type = quote! { #data_type.memsize() };
+    }
+    if options.free_immediately {
+        data_type = quote! { #data_type.free_immediately() };
+    }
+    if options.drop_in_background {
+        data_type = quote! { #data_type.drop_in_background() };
+    }
+    if options.embeddable {
+        data_type = quote! { #data_type.embeddable() };
+    }
+
+    let define_copy = if options.clone {
+        quote! {
+            fn define_copy(klass: ::ruby_wrap_data::__Value) {
+                ::ruby_wrap_data::define_clone::<#ident>(klass)
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let mut methods = Vec::new();
+    if options.inspect {
+        methods.push(quote! { ::ruby_wrap_data::define_inspect::<#ident>(klass); });
+    }
+    if options.to_s {
+        methods.push(quote! { ::ruby_wrap_data::define_to_s::<#ident>(klass); });
+    }
+    if options.eq {
+        methods.push(quote! { ::ruby_wrap_data::define_eq::<#ident>(klass); });
+    }
+    if options.hash {
+        methods.push(quote! { ::ruby_wrap_data::define_hash::<#ident>(klass); });
+    }
+    if options.cmp {
+        methods.push(quote! { ::ruby_wrap_data::define_cmp::<#ident>(klass); });
+    }
+    if options.each {
+        methods.push(quote! { ::ruby_wrap_data::define_each::<#ident>(klass); });
+    }
+    let define_methods = if methods.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            fn define_methods(klass: ::ruby_wrap_data::__Value) {
+                #(#methods)*
+            }
+        }
+    };
+
+    Ok(quote! {
+        impl ::ruby_wrap_data::TypedData for #ident {
+            fn data_type() -> &'static ::ruby_wrap_data::DataType<#ident> {
+                static DATA_TYPE: ::ruby_wrap_data::DataType<#ident> = #data_type;
+                &DATA_TYPE
+            }
+        }
+
+        impl ::ruby_wrap_data::RubyWrap for #ident {
+            const CLASS_NAME: &'static str = #class;
+            #define_copy
+            #define_methods
+        }
+    })
+}
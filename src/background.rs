@@ -0,0 +1,71 @@
+//! Dropping wrapped data on a background thread (see
+//! `DataType::drop_in_background`).
+//!
+//! The thread is started the first time it is needed and lives as long as
+//! the process. Nothing waits for it, so data still queued when the process
+//! exits is never dropped unless `flush_background_drops` is called first.
+
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use super::unwind::abort_on_panic;
+use super::Slot;
+
+enum Message {
+    Drop(Box<dyn Send>),
+    Flush(Sender<()>),
+}
+
+static QUEUE: Mutex<Option<Sender<Message>>> = Mutex::new(None);
+
+/// Blocks until all the data handed to the background thread so far has
+/// been dropped. Call this before the process exits (e.g. from an `at_exit`
+/// hook) if those drops have to happen.
+pub fn flush_background_drops() {
+    let queue = QUEUE.lock().unwrap().clone();
+    if let Some(queue) = queue {
+        let (done, flushed) = mpsc::channel();
+        queue.send(Message::Flush(done)).unwrap();
+        flushed.recv().unwrap();
+    }
+}
+
+pub(crate) unsafe fn drop_slot<T: Send + 'static>(slot: *mut Slot<T>) {
+    send(slot);
+    super::drop_slot::<T>(slot);
+}
+
+#[cfg(feature = "embed")]
+pub(crate) unsafe fn drop_slot_in_place<T: Send + 'static>(slot: *mut Slot<T>) {
+    send(slot);
+    super::drop_slot_in_place::<T>(slot);
+}
+
+// Hands the slot's data to the background thread, leaving the (now empty)
+// slot to the caller.
+unsafe fn send<T: Send + 'static>(slot: *mut Slot<T>) {
+    if let Some(data) = (*slot).data.take() {
+        queue().send(Message::Drop(Box::new(data))).unwrap();
+    }
+}
+
+fn queue() -> Sender<Message> {
+    QUEUE.lock().unwrap().get_or_insert_with(spawn).clone()
+}
+
+fn spawn() -> Sender<Message> {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("ruby_wrap_data drop".to_string())
+        .spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Drop(data) => abort_on_panic("drop", || drop(data)),
+                    Message::Flush(done) => done.send(()).unwrap(),
+                }
+            }
+        })
+        .expect("failed to start the background drop thread");
+    sender
+}
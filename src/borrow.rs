@@ -0,0 +1,174 @@
+//! Borrowing wrapped data in place.
+//!
+//! Unlike `remove` and `set`, nothing here takes the data out of the Ruby
+//! object. `with_ref` and `with_mut` release their borrow even if the
+//! closure panics or raises a Ruby exception, which is caught with
+//! `rb_protect` and raised again once the borrow is gone. The guards
+//! returned by `get_ref` and `get_mut` are only released when dropped, and
+//! only keep the object alive while they are on the stack, so those two are
+//! `unsafe`; prefer the closures.
+//!
+//! Borrows are tracked like a `RefCell`'s: any number of shared borrows, or
+//! a single mutable one. While the data is borrowed, it cannot be removed or
+//! replaced, so a Ruby method re-entering the same object gets a
+//! `WrapError::AlreadyBorrowed` rather than a dangling reference.
+
+use ruby_sys::types::Value;
+
+use std::ops::{Deref, DerefMut};
+
+use super::unwind::{jump_tag, protect};
+use super::{slot, slot_mut, Slot, WrapError};
+
+/// A shared borrow of the data wrapped by a Ruby object, returned by `get_ref`.
+pub struct DataRef<T> {
+    slot: *const Slot<T>,
+    datap: *const T,
+    // holding on to the object keeps it (and so the data) from being
+    // collected while the guard is on the stack
+    _object: Value,
+}
+
+impl<T> Deref for DataRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.datap }
+    }
+}
+
+impl<T> Drop for DataRef<T> {
+    fn drop(&mut self) {
+        let borrow = unsafe { &(*self.slot).borrow };
+        borrow.set(borrow.get() - 1);
+    }
+}
+
+/// A mutable borrow of the data wrapped by a Ruby object, returned by `get_mut`.
+pub struct DataRefMut<T> {
+    slot: *const Slot<T>,
+    datap: *mut T,
+    _object: Value,
+}
+
+impl<T> Deref for DataRefMut<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.datap }
+    }
+}
+
+impl<T> DerefMut for DataRefMut<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.datap }
+    }
+}
+
+impl<T> Drop for DataRefMut<T> {
+    fn drop(&mut self) {
+        unsafe { (*self.slot).borrow.set(0) };
+    }
+}
+
+/// Borrows the wrapped data from the given Ruby object without removing it.
+/// Returns `WrapError::Empty` if the object is currently empty, and
+/// `WrapError::AlreadyBorrowed` if it is mutably borrowed.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+///
+/// # Safety
+///
+/// The guard must stay on the stack, and be dropped before `object` can be
+/// collected: Ruby only sees references to objects held on the stack, so a
+/// guard moved into a `Box` or `Vec` can outlive the data it points to. A
+/// Ruby exception unwinds past the guard without dropping it, leaving the
+/// object borrowed for good, so don't call into Ruby while holding one.
+pub unsafe fn get_ref<T: 'static>(object: Value) -> Result<DataRef<T>, WrapError> {
+    let slot = slot::<T>(object)?;
+    let datap = datap(slot)?;
+    let borrow = &(*slot).borrow;
+    if borrow.get() < 0 {
+        return Err(WrapError::AlreadyBorrowed);
+    }
+    borrow.set(borrow.get() + 1);
+    Ok(DataRef {
+        slot,
+        datap,
+        _object: object,
+    })
+}
+
+/// Mutably borrows the wrapped data from the given Ruby object without
+/// removing it. Returns `WrapError::Empty` if the object is currently empty,
+/// `WrapError::Frozen` if it is frozen, and `WrapError::AlreadyBorrowed` if
+/// it is borrowed at all.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+///
+/// # Safety
+///
+/// Just as for `get_ref`.
+pub unsafe fn get_mut<T: 'static>(object: Value) -> Result<DataRefMut<T>, WrapError> {
+    let slot = slot_mut::<T>(object)?;
+    let datap = datap(slot)?;
+    (*slot).borrow.set(-1);
+    Ok(DataRefMut {
+        slot,
+        datap,
+        _object: object,
+    })
+}
+
+/// Calls `f` with a reference to the wrapped data and returns its result.
+/// Fails (without calling `f`) just like `get_ref`.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+/// * `f`      - a closure taking a `&T`
+///
+/// # Notes
+///
+/// This is the usual way to peek at the data:
+///
+/// ```rust,ignore
+/// let val = ruby_wrap_data::with_ref(thing, |data: &MyValue| data.val);
+/// ```
+pub fn with_ref<T: 'static, R, F: FnOnce(&T) -> R>(object: Value, f: F) -> Result<R, WrapError> {
+    // the guard never leaves this frame, and `protect` keeps a Ruby
+    // exception from unwinding past it
+    let data = unsafe { get_ref(object)? };
+    let result = protect(|| f(&data));
+    // released before any exception carries on
+    drop(data);
+    Ok(result.unwrap_or_else(|state| jump_tag(state)))
+}
+
+/// Calls `f` with a mutable reference to the wrapped data and returns its
+/// result. Fails (without calling `f`) just like `get_mut`.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+/// * `f`      - a closure taking a `&mut T`
+pub fn with_mut<T: 'static, R, F: FnOnce(&mut T) -> R>(
+    object: Value,
+    f: F,
+) -> Result<R, WrapError> {
+    let mut data = unsafe { get_mut(object)? };
+    let result = protect(|| f(&mut data));
+    drop(data);
+    Ok(result.unwrap_or_else(|state| jump_tag(state)))
+}
+
+fn datap<T>(slot: *mut Slot<T>) -> Result<*mut T, WrapError> {
+    match unsafe { (*slot).data.as_mut() } {
+        Some(data) => Ok(data as *mut T),
+        None => Err(WrapError::Empty),
+    }
+}
@@ -0,0 +1,79 @@
+//! Defining Ruby classes backed by wrapped Rust types.
+
+use ruby_sys::rb_cObject;
+use ruby_sys::types::Value;
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+
+use super::{define_alloc_func, forbid_clone, rb_intern, wrap_typed_value, TypedData};
+
+type Id = usize;
+
+extern "C" {
+    fn rb_const_defined_at(module: Value, id: Id) -> c_int;
+    fn rb_const_get_at(module: Value, id: Id) -> Value;
+    fn rb_define_module_under(outer: Value, name: *const c_char) -> Value;
+    fn rb_define_class_under(outer: Value, name: *const c_char, superclass: Value) -> Value;
+}
+
+/// Implemented by types that back a Ruby class, usually via
+/// `#[derive(RubyWrap)]` (see the `derive` feature).
+pub trait RubyWrap: TypedData + Default {
+    /// The name of the Ruby class, including any modules it is nested in,
+    /// e.g. `Geometry::Point`.
+    const CLASS_NAME: &'static str;
+
+    /// Defines the Ruby class, along with any modules it is nested in that
+    /// don't exist yet, and gives it an alloc function that wraps
+    /// `Self::default()`. Returns the class.
+    fn define_class() -> Value {
+        define_class::<Self>()
+    }
+
+    /// Called by `define_class` to define how instances are copied (with
+    /// `dup` and `clone`). By default they can't be, and copying raises a
+    /// `TypeError`; types implementing `Clone` can call `define_clone` here
+    /// instead (or derive with `#[ruby(clone)]`).
+    fn define_copy(klass: Value) {
+        forbid_clone(klass);
+    }
+
+    /// Called by `define_class` last, to define any other methods the class
+    /// should have, such as `inspect` (see `define_inspect`). Does nothing
+    /// by default.
+    fn define_methods(_klass: Value) {}
+}
+
+fn define_class<T: RubyWrap>() -> Value {
+    let mut names: Vec<&str> = T::CLASS_NAME.split("::").collect();
+    let class_name = names.pop().unwrap();
+    let mut outer = unsafe { rb_cObject };
+    for name in names {
+        outer = module_under(outer, name);
+    }
+    let class_name = CString::new(class_name).unwrap();
+    let klass = unsafe { rb_define_class_under(outer, class_name.as_ptr(), rb_cObject) };
+    define_alloc_func(klass, alloc::<T>);
+    T::define_copy(klass);
+    T::define_methods(klass);
+    klass
+}
+
+// Enclosing modules may already exist, possibly as classes, in which case
+// `rb_define_module_under` would raise, so look them up first.
+fn module_under(outer: Value, name: &str) -> Value {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        let id = rb_intern(name.as_ptr());
+        if rb_const_defined_at(outer, id) != 0 {
+            rb_const_get_at(outer, id)
+        } else {
+            rb_define_module_under(outer, name.as_ptr())
+        }
+    }
+}
+
+fn alloc<T: RubyWrap>(klass: Value) -> Value {
+    wrap_typed_value(klass, T::default())
+}
@@ -0,0 +1,118 @@
+//! Comparing wrapped objects by their data.
+//!
+//! The data is only compared with that of objects wrapping the same Rust
+//! type; anything else (including empty objects) is equal only to itself,
+//! as with Ruby's default methods (or incomparable, for `<=>`).
+
+use ruby_sys::types::{CallbackPtr, Value};
+
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use super::method::{boolean, call, define_method, Failure};
+use super::{rb_include_module, Nil};
+use super::{with_ref, WrapError};
+
+extern "C" {
+    static rb_mComparable: Value;
+    fn rb_int2inum(n: isize) -> Value;
+    fn rb_obj_id(object: Value) -> Value;
+}
+
+/// Defines `==` for a Ruby class wrapping a `T`, comparing the data with
+/// `PartialEq`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_eq<T: PartialEq + 'static>(klass: Value) {
+    let func = eq::<T> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"==\0", func as CallbackPtr, 1);
+}
+
+/// Defines `eql?` and `hash` for a Ruby class wrapping a `T`, so instances
+/// holding equal data are the same Hash key.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_hash<T: Eq + Hash + 'static>(klass: Value) {
+    let eql = eq::<T> as extern "C" fn(Value, Value) -> Value;
+    let hash = hash::<T> as extern "C" fn(Value) -> Value;
+    define_method(klass, b"eql?\0", eql as CallbackPtr, 1);
+    define_method(klass, b"hash\0", hash as CallbackPtr, 0);
+}
+
+/// Defines `<=>` for a Ruby class wrapping a `T`, comparing the data with
+/// `PartialOrd`, and includes `Comparable`. Objects that can't be compared
+/// give `nil`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_cmp<T: PartialOrd + 'static>(klass: Value) {
+    let func = cmp::<T> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"<=>\0", func as CallbackPtr, 1);
+    unsafe { rb_include_module(klass, rb_mComparable) };
+}
+
+extern "C" fn eq<T: PartialEq + 'static>(object: Value, other: Value) -> Value {
+    call(|| {
+        if object.value == other.value {
+            return Ok(boolean(true));
+        }
+        let equal = compare(object, other, |data: &T, other: &T| data == other)?;
+        Ok(boolean(equal.unwrap_or(false)))
+    })
+}
+
+extern "C" fn hash<T: Hash + 'static>(object: Value) -> Value {
+    call(|| {
+        let mut hasher = DefaultHasher::new();
+        match with_ref(object, |data: &T| data.hash(&mut hasher)) {
+            Ok(()) => {}
+            // empty objects are only equal to themselves
+            Err(WrapError::Empty) => unsafe { rb_obj_id(object) }.value.hash(&mut hasher),
+            Err(error) => return Err(error.into()),
+        }
+        // keeps it a Fixnum
+        Ok(unsafe { rb_int2inum(hasher.finish() as isize >> 2) })
+    })
+}
+
+extern "C" fn cmp<T: PartialOrd + 'static>(object: Value, other: Value) -> Value {
+    call(|| {
+        let ordering = if object.value == other.value {
+            Some(Ordering::Equal)
+        } else {
+            compare(object, other, |data: &T, other: &T| data.partial_cmp(other))?
+                .and_then(|ordering| ordering)
+        };
+        Ok(match ordering {
+            Some(ordering) => unsafe { rb_int2inum(ordering as isize) },
+            None => Value {
+                value: Nil as usize,
+            },
+        })
+    })
+}
+
+// Calls `f` with the data of both objects, or returns `None` if `other`
+// doesn't wrap a `T` or either object is empty.
+fn compare<T: 'static, R, F: FnOnce(&T, &T) -> R>(
+    object: Value,
+    other: Value,
+    f: F,
+) -> Result<Option<R>, Failure> {
+    match with_ref(object, |data: &T| {
+        with_ref(other, |other: &T| f(data, other))
+    }) {
+        Ok(Ok(result)) => Ok(Some(result)),
+        Err(WrapError::Empty)
+        | Ok(Err(WrapError::Empty))
+        | Ok(Err(WrapError::NotData))
+        | Ok(Err(WrapError::TypeMismatch)) => Ok(None),
+        Err(error) | Ok(Err(error)) => Err(error.into()),
+    }
+}
@@ -0,0 +1,178 @@
+//! Support for `GC.compact` (Ruby 2.7 and up, behind the `compact` feature).
+//!
+//! Values marked with `Mark::mark` are pinned, so compaction never moves
+//! them. Typed data implementing `Compact` instead marks its values as
+//! movable and has Ruby call back into it afterwards to update them to
+//! wherever they ended up.
+
+use ruby_sys::types::{c_void, Value};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::BuildHasher;
+
+use super::unwind::abort_on_panic;
+use super::{Mark, Slot};
+
+extern "C" {
+    fn rb_gc_mark_movable(value: Value);
+    fn rb_gc_location(value: Value) -> Value;
+}
+
+/// Implemented by wrapped data whose Ruby values may be moved by `GC.compact`.
+///
+/// ```rust,ignore
+/// impl Compact for Node {
+///     fn mark_movable(&self) {
+///         self.name.mark_movable();
+///         self.children.mark_movable();
+///     }
+///
+///     fn compact(&mut self) {
+///         self.name.compact();
+///         self.children.compact();
+///     }
+/// }
+/// ```
+pub trait Compact: Mark {
+    /// Marks every Ruby value reachable from `self` without pinning it.
+    fn mark_movable(&self);
+
+    /// Updates every Ruby value reachable from `self` to its new location.
+    fn compact(&mut self);
+}
+
+impl Compact for Value {
+    fn mark_movable(&self) {
+        unsafe { rb_gc_mark_movable(*self) };
+    }
+
+    fn compact(&mut self) {
+        *self = unsafe { rb_gc_location(*self) };
+    }
+}
+
+impl<T: Compact + ?Sized> Compact for Box<T> {
+    fn mark_movable(&self) {
+        (**self).mark_movable();
+    }
+
+    fn compact(&mut self) {
+        (**self).compact();
+    }
+}
+
+impl<T: Compact> Compact for Option<T> {
+    fn mark_movable(&self) {
+        if let Some(ref value) = *self {
+            value.mark_movable();
+        }
+    }
+
+    fn compact(&mut self) {
+        if let Some(ref mut value) = *self {
+            value.compact();
+        }
+    }
+}
+
+impl<T: Compact> Compact for [T] {
+    fn mark_movable(&self) {
+        for value in self {
+            value.mark_movable();
+        }
+    }
+
+    fn compact(&mut self) {
+        for value in self {
+            value.compact();
+        }
+    }
+}
+
+impl<T: Compact> Compact for Vec<T> {
+    fn mark_movable(&self) {
+        self[..].mark_movable();
+    }
+
+    fn compact(&mut self) {
+        self[..].compact();
+    }
+}
+
+impl<T: Compact> Compact for VecDeque<T> {
+    fn mark_movable(&self) {
+        for value in self {
+            value.mark_movable();
+        }
+    }
+
+    fn compact(&mut self) {
+        for value in self {
+            value.compact();
+        }
+    }
+}
+
+impl<K, V: Compact, S: BuildHasher> Compact for HashMap<K, V, S> {
+    fn mark_movable(&self) {
+        for value in self.values() {
+            value.mark_movable();
+        }
+    }
+
+    fn compact(&mut self) {
+        for value in self.values_mut() {
+            value.compact();
+        }
+    }
+}
+
+impl<K, V: Compact> Compact for BTreeMap<K, V> {
+    fn mark_movable(&self) {
+        for value in self.values() {
+            value.mark_movable();
+        }
+    }
+
+    fn compact(&mut self) {
+        for value in self.values_mut() {
+            value.compact();
+        }
+    }
+}
+
+impl<A: Compact, B: Compact> Compact for (A, B) {
+    fn mark_movable(&self) {
+        self.0.mark_movable();
+        self.1.mark_movable();
+    }
+
+    fn compact(&mut self) {
+        self.0.compact();
+        self.1.compact();
+    }
+}
+
+impl<A: Compact, B: Compact, C: Compact> Compact for (A, B, C) {
+    fn mark_movable(&self) {
+        self.0.mark_movable();
+        self.1.mark_movable();
+        self.2.mark_movable();
+    }
+
+    fn compact(&mut self) {
+        self.0.compact();
+        self.1.compact();
+        self.2.compact();
+    }
+}
+
+pub(crate) extern "C" fn mark_movable<T: Compact>(data: *mut c_void) {
+    let slot = unsafe { &*(data as *const Slot<T>) };
+    abort_on_panic("mark", || slot.data.mark_movable());
+}
+
+pub(crate) extern "C" fn compact<T: Compact>(data: *mut c_void) {
+    let slot = unsafe { &mut *(data as *mut Slot<T>) };
+    abort_on_panic("compact", || slot.data.compact());
+}
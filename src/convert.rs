@@ -0,0 +1,221 @@
+//! Converting between Rust and Ruby values, for the methods this crate
+//! defines (e.g. the items `define_each` yields, or the operands of `+`).
+
+use ruby_sys::types::Value;
+use ruby_sys::value::RubySpecialConsts::{False, Nil, True};
+
+use std::convert::TryFrom;
+use std::os::raw::{c_char, c_long};
+use std::slice;
+
+use super::{builtin_type, is_special_const, with_ref, TypedData, T_STRING};
+
+extern "C" {
+    static rb_cFloat: Value;
+    fn rb_obj_is_kind_of(object: Value, klass: Value) -> Value;
+    fn rb_num2dbl(number: Value) -> f64;
+    pub(crate) fn rb_str_bytesize(string: Value) -> Value;
+    pub(crate) fn rb_string_value_ptr(string: *mut Value) -> *const c_char;
+    fn rb_ll2inum(n: i64) -> Value;
+    fn rb_ull2inum(n: u64) -> Value;
+    fn rb_float_new(d: f64) -> Value;
+    pub(crate) fn rb_utf8_str_new(ptr: *const c_char, len: c_long) -> Value;
+    pub(crate) fn rb_assoc_new(first: Value, second: Value) -> Value;
+}
+
+/// Converts a Rust value into a Ruby one.
+///
+/// Implemented for Ruby values themselves, `bool`, the numeric types,
+/// strings (as UTF-8 `String`s), `Option` (with `None` as `nil`), and pairs
+/// (as two-element `Array`s, as Ruby's `Hash#each` yields). References to
+/// any of these convert a clone. Implement it for your own types, say by
+/// wrapping them, to yield them from `define_each`.
+pub trait IntoValue {
+    /// Performs the conversion.
+    fn into_value(self) -> Value;
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        let value = if self { True } else { False };
+        Value {
+            value: value as usize,
+        }
+    }
+}
+
+macro_rules! signed {
+    ($($t:ty)*) => {$(
+        impl IntoValue for $t {
+            fn into_value(self) -> Value {
+                unsafe { rb_ll2inum(self as i64) }
+            }
+        }
+    )*};
+}
+
+macro_rules! unsigned {
+    ($($t:ty)*) => {$(
+        impl IntoValue for $t {
+            fn into_value(self) -> Value {
+                unsafe { rb_ull2inum(self as u64) }
+            }
+        }
+    )*};
+}
+
+signed!(i8 i16 i32 i64 isize);
+unsigned!(u8 u16 u32 u64 usize);
+
+impl IntoValue for f32 {
+    fn into_value(self) -> Value {
+        unsafe { rb_float_new(f64::from(self)) }
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        unsafe { rb_float_new(self) }
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        unsafe { rb_utf8_str_new(self.as_ptr() as *const c_char, self.len() as c_long) }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        self.as_str().into_value()
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        match self {
+            Some(value) => value.into_value(),
+            None => Value {
+                value: Nil as usize,
+            },
+        }
+    }
+}
+
+impl<A: IntoValue, B: IntoValue> IntoValue for (A, B) {
+    fn into_value(self) -> Value {
+        let first = self.0.into_value();
+        let second = self.1.into_value();
+        unsafe { rb_assoc_new(first, second) }
+    }
+}
+
+impl<T: IntoValue + Clone> IntoValue for &T {
+    fn into_value(self) -> Value {
+        self.clone().into_value()
+    }
+}
+
+/// Converts a Ruby value into a Rust one.
+///
+/// Implemented for Ruby values themselves, `bool` (from `true` and `false`
+/// only), the integer types (from Integers small enough to be Fixnums and
+/// within the type's range), the float types (from Floats and Fixnums),
+/// `String` (from UTF-8 Strings), `Option` (with `nil` as `None`), and
+/// every `TypedData` type that is `Clone`, from objects wrapping one.
+pub trait FromValue: Sized {
+    /// Performs the conversion, returning `None` if the value isn't one
+    /// this type can be converted from.
+    fn from_value(value: Value) -> Option<Self>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Option<Value> {
+        Some(value)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Option<bool> {
+        match value.value {
+            v if v == True as usize => Some(true),
+            v if v == False as usize => Some(false),
+            _ => None,
+        }
+    }
+}
+
+fn fixnum(value: Value) -> Option<isize> {
+    if value.value & 1 == 1 {
+        Some(value.value as isize >> 1)
+    } else {
+        None
+    }
+}
+
+macro_rules! integer {
+    ($($t:ty)*) => {$(
+        impl FromValue for $t {
+            fn from_value(value: Value) -> Option<$t> {
+                fixnum(value).and_then(|n| <$t>::try_from(n).ok())
+            }
+        }
+    )*};
+}
+
+integer!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize);
+
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Option<f64> {
+        if let Some(n) = fixnum(value) {
+            return Some(n as f64);
+        }
+        if unsafe { rb_obj_is_kind_of(value, rb_cFloat) }.value == True as usize {
+            Some(unsafe { rb_num2dbl(value) })
+        } else {
+            None
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: Value) -> Option<f32> {
+        f64::from_value(value).map(|d| d as f32)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(mut value: Value) -> Option<String> {
+        if is_special_const(value) || builtin_type(value) != T_STRING {
+            return None;
+        }
+        let bytes = unsafe {
+            let ptr = rb_string_value_ptr(&mut value) as *const u8;
+            let len = fixnum(rb_str_bytesize(value)).unwrap_or(0) as usize;
+            slice::from_raw_parts(ptr, len)
+        };
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Option<Option<T>> {
+        if value.value == Nil as usize {
+            Some(None)
+        } else {
+            T::from_value(value).map(Some)
+        }
+    }
+}
+
+impl<T: TypedData + Clone> FromValue for T {
+    fn from_value(value: Value) -> Option<T> {
+        with_ref(value, T::clone).ok()
+    }
+}
@@ -0,0 +1,51 @@
+//! Supporting `dup` and `clone` on wrapped objects.
+//!
+//! Ruby copies an object by allocating a new one of the same class (so with
+//! the class's alloc function) and then calling `initialize_copy` on it,
+//! which by default copies nothing of the wrapped data. These define an
+//! `initialize_copy` that either clones the data or refuses to copy at all.
+
+use ruby_sys::types::{CallbackPtr, Value};
+
+use super::error::rb_eTypeError;
+use super::method::{call, class_name, define_method, Failure};
+use super::{set_value, with_ref};
+
+/// Defines `initialize_copy` for a Ruby class wrapping a `T`, so `dup` and
+/// `clone` give the copy a clone of the original's data.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_clone<T: Clone + 'static>(klass: Value) {
+    let func = initialize_copy::<T> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"initialize_copy\0", func as CallbackPtr, 1);
+}
+
+/// Defines `initialize_copy` for a Ruby class so that `dup` and `clone`
+/// raise a `TypeError`, for wrapped types that can't be copied.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+pub fn forbid_clone(klass: Value) {
+    let func = initialize_copy_forbidden as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"initialize_copy\0", func as CallbackPtr, 1);
+}
+
+extern "C" fn initialize_copy<T: Clone + 'static>(copy: Value, orig: Value) -> Value {
+    call(|| {
+        if copy.value != orig.value {
+            let data = with_ref(orig, T::clone)?;
+            set_value(copy, data)?;
+        }
+        Ok(copy)
+    })
+}
+
+extern "C" fn initialize_copy_forbidden(copy: Value, _orig: Value) -> Value {
+    call(|| {
+        let message = format!("can't copy {}", class_name(copy));
+        Err(Failure::new(unsafe { rb_eTypeError }, message))
+    })
+}
@@ -0,0 +1,72 @@
+//! Iterating wrapped collections from Ruby.
+//!
+//! The data stays borrowed for the whole iteration, so the block can read
+//! it (or iterate it again) but not remove or replace it. Ruby exceptions
+//! and `break`s out of the block are caught with `rb_protect` so the borrow
+//! is released before they carry on up the stack.
+
+use ruby_sys::types::{CallbackPtr, Value};
+
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+use super::convert::IntoValue;
+use super::method::{call, define_method};
+use super::unwind::{jump_tag, protect};
+use super::{rb_include_module, rb_intern, with_ref};
+
+extern "C" {
+    static rb_mEnumerable: Value;
+    fn rb_block_given_p() -> c_int;
+    fn rb_yield(value: Value) -> Value;
+    fn rb_id2sym(id: usize) -> Value;
+    fn rb_enumeratorize(object: Value, method: Value, argc: c_int, argv: *const Value) -> Value;
+}
+
+/// Defines `each` for a Ruby class wrapping a `T`, yielding every item of
+/// `&T`'s iterator converted with `IntoValue` (or returning an `Enumerator`
+/// without a block), and includes `Enumerable`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_each<T: 'static>(klass: Value)
+where
+    for<'a> &'a T: IntoIterator,
+    for<'a> <&'a T as IntoIterator>::Item: IntoValue,
+{
+    let func = each::<T> as extern "C" fn(Value) -> Value;
+    define_method(klass, b"each\0", func as CallbackPtr, 0);
+    unsafe { rb_include_module(klass, rb_mEnumerable) };
+}
+
+extern "C" fn each<T: 'static>(object: Value) -> Value
+where
+    for<'a> &'a T: IntoIterator,
+    for<'a> <&'a T as IntoIterator>::Item: IntoValue,
+{
+    if unsafe { rb_block_given_p() } == 0 {
+        return unsafe {
+            let each = rb_id2sym(rb_intern(b"each\0".as_ptr() as *const c_char));
+            rb_enumeratorize(object, each, 0, ptr::null())
+        };
+    }
+
+    let mut state = None;
+    call(|| {
+        with_ref(object, |data: &T| {
+            for item in data {
+                if let Err(tag) = protect(|| unsafe { rb_yield(item.into_value()) }) {
+                    state = Some(tag);
+                    break;
+                }
+            }
+        })?;
+        Ok(object)
+    });
+    // carried on only once `call` is done, as it can't be unwound past
+    if let Some(state) = state {
+        jump_tag(state);
+    }
+    object
+}
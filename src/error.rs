@@ -0,0 +1,88 @@
+use ruby_sys::types::Value;
+
+use std::error::Error;
+use std::fmt;
+use std::os::raw::{c_char, c_long};
+
+extern "C" {
+    pub(crate) static rb_eTypeError: Value;
+    pub(crate) static rb_eRuntimeError: Value;
+    static rb_eFrozenError: Value;
+    fn rb_exc_new(klass: Value, ptr: *const c_char, len: c_long) -> Value;
+    fn rb_exc_raise(exception: Value) -> !;
+}
+
+/// The ways getting at wrapped data can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapError {
+    /// The object does not wrap any data created by this crate.
+    NotData,
+    /// The object wraps the right type, but currently holds no data.
+    Empty,
+    /// The object wraps a different Rust type than the one asked for.
+    TypeMismatch,
+    /// The object is frozen, so its data cannot be changed.
+    Frozen,
+    /// The data is borrowed elsewhere (e.g. by `get_ref` or `with_mut`)
+    /// in a way that conflicts with this access.
+    AlreadyBorrowed,
+    /// The data holds Ruby values for the GC to mark, so it can't be used
+    /// without the GVL.
+    Marked,
+}
+
+impl WrapError {
+    /// Returns the Ruby exception class matching this error.
+    pub fn exception_class(&self) -> Value {
+        unsafe {
+            match *self {
+                WrapError::NotData | WrapError::TypeMismatch | WrapError::Marked => rb_eTypeError,
+                WrapError::Frozen => rb_eFrozenError,
+                WrapError::Empty | WrapError::AlreadyBorrowed => rb_eRuntimeError,
+            }
+        }
+    }
+
+    /// Raises this error as a Ruby exception of the matching class.
+    ///
+    /// Raising jumps straight back into Ruby without unwinding, so anything
+    /// still owned by the calling Rust frames is leaked. It is meant for the
+    /// outermost layer of your method glue:
+    ///
+    /// ```rust,ignore
+    /// let len = ruby_wrap_data::with_ref(itself, |list: &List| list.len())
+    ///     .unwrap_or_else(|error| error.raise());
+    /// ```
+    pub fn raise(self) -> ! {
+        raise(self.exception_class(), self.to_string())
+    }
+}
+
+impl fmt::Display for WrapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match *self {
+            WrapError::NotData => "object does not wrap any Rust data",
+            WrapError::Empty => "wrapped data has been removed",
+            WrapError::TypeMismatch => "wrapped data is not of the requested type",
+            WrapError::Frozen => "can't modify the data of a frozen object",
+            WrapError::AlreadyBorrowed => "wrapped data is already borrowed",
+            WrapError::Marked => "wrapped data holding Ruby values can't be used without the GVL",
+        };
+        f.write_str(message)
+    }
+}
+
+impl Error for WrapError {}
+
+pub(crate) fn raise(klass: Value, message: String) -> ! {
+    let exception = unsafe {
+        rb_exc_new(
+            klass,
+            message.as_ptr() as *const c_char,
+            message.len() as c_long,
+        )
+    };
+    // raising jumps straight past this frame, so clean up first
+    drop(message);
+    unsafe { rb_exc_raise(exception) }
+}
@@ -0,0 +1,136 @@
+//! Support for wrapped data that holds on to Ruby values.
+//!
+//! Ruby's GC cannot see inside a `Box<T>`, so any `Value` stored there will
+//! be collected unless the wrapped data marks it. Implement `Mark` for your
+//! type and wrap it with `wrap_marked` (or `DataType::mark` for typed data)
+//! to have Ruby call back into it during the mark phase.
+
+use ruby_sys::types::{c_void, Value};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use super::unwind::abort_on_panic;
+use super::Slot;
+
+extern "C" {
+    pub(crate) fn rb_gc_mark(value: Value);
+}
+
+/// Implemented by wrapped data that holds Ruby values.
+///
+/// ```rust,ignore
+/// struct Node {
+///     name: Value,
+///     children: Vec<Value>,
+/// }
+///
+/// impl Mark for Node {
+///     fn mark(&self) {
+///         self.name.mark();
+///         self.children.mark();
+///     }
+/// }
+/// ```
+pub trait Mark {
+    /// Marks every Ruby value reachable from `self`.
+    fn mark(&self);
+}
+
+impl Mark for Value {
+    fn mark(&self) {
+        unsafe { rb_gc_mark(*self) };
+    }
+}
+
+impl<T: Mark + ?Sized> Mark for &T {
+    fn mark(&self) {
+        (**self).mark();
+    }
+}
+
+impl<T: Mark + ?Sized> Mark for Box<T> {
+    fn mark(&self) {
+        (**self).mark();
+    }
+}
+
+impl<T: Mark + ?Sized> Mark for Rc<T> {
+    fn mark(&self) {
+        (**self).mark();
+    }
+}
+
+impl<T: Mark + ?Sized> Mark for Arc<T> {
+    fn mark(&self) {
+        (**self).mark();
+    }
+}
+
+impl<T: Mark> Mark for Option<T> {
+    fn mark(&self) {
+        if let Some(ref value) = *self {
+            value.mark();
+        }
+    }
+}
+
+impl<T: Mark> Mark for [T] {
+    fn mark(&self) {
+        for value in self {
+            value.mark();
+        }
+    }
+}
+
+impl<T: Mark> Mark for Vec<T> {
+    fn mark(&self) {
+        self[..].mark();
+    }
+}
+
+impl<T: Mark> Mark for VecDeque<T> {
+    fn mark(&self) {
+        for value in self {
+            value.mark();
+        }
+    }
+}
+
+impl<K, V: Mark, S: BuildHasher> Mark for HashMap<K, V, S> {
+    fn mark(&self) {
+        for value in self.values() {
+            value.mark();
+        }
+    }
+}
+
+impl<K, V: Mark> Mark for BTreeMap<K, V> {
+    fn mark(&self) {
+        for value in self.values() {
+            value.mark();
+        }
+    }
+}
+
+impl<A: Mark, B: Mark> Mark for (A, B) {
+    fn mark(&self) {
+        self.0.mark();
+        self.1.mark();
+    }
+}
+
+impl<A: Mark, B: Mark, C: Mark> Mark for (A, B, C) {
+    fn mark(&self) {
+        self.0.mark();
+        self.1.mark();
+        self.2.mark();
+    }
+}
+
+pub(crate) extern "C" fn mark<T: Mark>(data: *mut c_void) {
+    let slot = unsafe { &*(data as *const Slot<T>) };
+    abort_on_panic("mark", || slot.data.mark());
+}
@@ -0,0 +1,207 @@
+//! Working on wrapped data without holding Ruby's Global VM Lock.
+//!
+//! While the GVL is released, other Ruby threads run alongside the closure,
+//! so the data is marked as borrowed for the duration: mutably by
+//! `without_gvl` (refusing every other access), or shared by
+//! `without_gvl_ref` (which lets other threads read it too). Data the GC
+//! marks is refused outright, since the GC could read it at any moment, and
+//! using this crate from inside the closure panics.
+
+use ruby_sys::types::{c_void, Value};
+
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::thread;
+
+use super::unwind::abort_on_panic;
+use super::{data_funcs, rdata, shared, slot, slot_mut, WrapError};
+
+extern "C" {
+    fn rb_thread_call_without_gvl2(
+        func: extern "C" fn(*mut c_void) -> *mut c_void,
+        data1: *mut c_void,
+        ubf: Option<extern "C" fn(*mut c_void)>,
+        data2: *mut c_void,
+    ) -> *mut c_void;
+    fn rb_thread_check_ints();
+}
+
+// What `Slot::borrow` holds while the data is out with `without_gvl`.
+pub(crate) const UNLOCKED: isize = -2;
+
+thread_local! {
+    static WITHOUT_GVL: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Calls `f` with a mutable reference to the wrapped data, releasing the GVL
+/// so other Ruby threads can run in the meantime, and returns its result.
+/// Fails (without calling `f`) just like `get_mut`, and also with
+/// `WrapError::Marked` if the object marks the Ruby values its data holds.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+/// * `f`      - a closure taking a `&mut T`
+///
+/// # Notes
+///
+/// `f` must not call into Ruby at all, since it doesn't hold the GVL; calling
+/// this crate's functions from inside it panics. A panic in `f` is passed on
+/// once the GVL is back:
+///
+/// ```rust,ignore
+/// let pixels = ruby_wrap_data::without_gvl(itself, |image: &mut Image| image.decode());
+/// ```
+///
+/// Ruby can't interrupt `f` (e.g. for `Thread#kill` or Ctrl-C); use
+/// `without_gvl_unblock` for work that may take a while.
+pub fn without_gvl<T, R, F>(object: Value, f: F) -> Result<R, WrapError>
+where
+    T: Send + 'static,
+    R: Send,
+    F: FnOnce(&mut T) -> R + Send,
+{
+    let f = |data: *mut T| f(unsafe { &mut *data });
+    call_without_gvl(object, false, f, None::<fn()>)
+}
+
+/// Like `without_gvl`, but when Ruby needs to interrupt the thread, it calls
+/// `unblock` (from another thread) to ask `f` to return early, e.g. by
+/// setting a flag `f` checks.
+///
+/// # Arguments
+///
+/// * `object`  - a Ruby object created by this crate
+/// * `f`       - a closure taking a `&mut T`
+/// * `unblock` - a closure making `f` return soon
+///
+/// # Notes
+///
+/// A panic in `unblock` aborts the process.
+pub fn without_gvl_unblock<T, R, F, U>(object: Value, f: F, unblock: U) -> Result<R, WrapError>
+where
+    T: Send + 'static,
+    R: Send,
+    F: FnOnce(&mut T) -> R + Send,
+    U: Fn() + Sync,
+{
+    let f = |data: *mut T| f(unsafe { &mut *data });
+    call_without_gvl(object, false, f, Some(unblock))
+}
+
+/// Like `without_gvl`, but calls `f` with a shared reference to the data.
+/// This only needs a shared borrow, so it works on frozen objects, and other
+/// threads can keep reading the data while `f` runs (hence `T: Sync`). Fails
+/// (without calling `f`) just like `get_ref`, and also with
+/// `WrapError::Marked` if the object marks the Ruby values its data holds.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+/// * `f`      - a closure taking a `&T`
+pub fn without_gvl_ref<T, R, F>(object: Value, f: F) -> Result<R, WrapError>
+where
+    T: Sync + 'static,
+    R: Send,
+    F: FnOnce(&T) -> R + Send,
+{
+    let f = |data: *mut T| f(unsafe { &*data });
+    call_without_gvl(object, true, f, None::<fn()>)
+}
+
+// Panics if the calling thread has released the GVL with `without_gvl`.
+pub(crate) fn check() {
+    let without_gvl = WITHOUT_GVL.with(|flag| flag.get());
+    assert!(!without_gvl, "ruby_wrap_data used without the GVL");
+}
+
+struct Call<'a, T: 'a, R, F: 'a> {
+    f: &'a mut Option<F>,
+    data: *mut T,
+    result: Option<thread::Result<R>>,
+}
+
+// Hands `f` the data with the GVL released, borrowing it mutably or, if
+// `shared`, alongside any other shared borrows.
+fn call_without_gvl<T, R, F, U>(
+    object: Value,
+    shared: bool,
+    f: F,
+    unblock: Option<U>,
+) -> Result<R, WrapError>
+where
+    T: 'static,
+    R: Send,
+    F: FnOnce(*mut T) -> R + Send,
+    U: Fn() + Sync,
+{
+    let mut f = Some(f);
+    let (ubf, data2) = match unblock {
+        Some(ref unblock) => (
+            Some(call_unblock::<U> as extern "C" fn(*mut c_void)),
+            unblock as *const U as *mut c_void,
+        ),
+        None => (None, ptr::null_mut()),
+    };
+    loop {
+        let slot = if shared {
+            slot::<T>(object)?
+        } else {
+            slot_mut::<T>(object)?
+        };
+        let borrow = unsafe { &(*slot).borrow };
+        if borrow.get() < 0 {
+            return Err(WrapError::AlreadyBorrowed);
+        }
+        let (dmark, _) = data_funcs(rdata(object)?);
+        if dmark.is_some() && !shared::is_pin(dmark) {
+            return Err(WrapError::Marked);
+        }
+        let data = match unsafe { (*slot).data.as_mut() } {
+            Some(data) => data as *mut T,
+            None => return Err(WrapError::Empty),
+        };
+
+        let mut call = Call {
+            f: &mut f,
+            data,
+            result: None,
+        };
+        borrow.set(if shared { borrow.get() + 1 } else { UNLOCKED });
+        unsafe {
+            rb_thread_call_without_gvl2(
+                call_f::<T, R, F>,
+                &mut call as *mut Call<T, R, F> as *mut c_void,
+                ubf,
+                data2,
+            )
+        };
+        // other threads may have come and gone with shared borrows meanwhile
+        borrow.set(if shared { borrow.get() - 1 } else { 0 });
+
+        match call.result {
+            Some(Ok(result)) => return Ok(result),
+            Some(Err(cause)) => panic::resume_unwind(cause),
+            // Ruby was interrupted before `f` got to run; let it handle that
+            // (which may well raise) and then try again, since the handler
+            // might have changed the object
+            None => unsafe { rb_thread_check_ints() },
+        }
+    }
+}
+
+extern "C" fn call_f<T, R, F: FnOnce(*mut T) -> R>(call: *mut c_void) -> *mut c_void {
+    let call = unsafe { &mut *(call as *mut Call<T, R, F>) };
+    let f = call.f.take().unwrap();
+    let data = call.data;
+    WITHOUT_GVL.with(|flag| flag.set(true));
+    call.result = Some(panic::catch_unwind(AssertUnwindSafe(|| f(data))));
+    WITHOUT_GVL.with(|flag| flag.set(false));
+    ptr::null_mut()
+}
+
+extern "C" fn call_unblock<U: Fn()>(unblock: *mut c_void) {
+    let unblock = unsafe { &*(unblock as *const U) };
+    abort_on_panic("unblock", unblock);
+}
@@ -0,0 +1,114 @@
+//! `inspect` and `to_s` for wrapped objects, from the data's `Debug` and
+//! `Display` implementations.
+//!
+//! An object that can't be borrowed right now prints as `#<Thing (empty)>`
+//! or `#<Thing (borrowed)>` instead. Formatting may call back into Ruby
+//! (say, to inspect a Ruby value the data holds), which may lead back to an
+//! object already being formatted on this thread; that prints as
+//! `#<Thing ...>`, like Ruby's own recursive arrays and hashes. Should that
+//! raise, the exception is caught until the object is no longer marked as
+//! being formatted.
+
+use ruby_sys::types::{CallbackPtr, Value};
+use ruby_sys::value::RubySpecialConsts::Nil;
+
+use std::cell::RefCell;
+use std::fmt::{Debug, Display};
+use std::os::raw::{c_char, c_long};
+
+use super::convert::rb_utf8_str_new;
+use super::method::{call, class_name, define_method};
+use super::unwind::{jump_tag, protect};
+use super::{with_ref, WrapError};
+
+thread_local! {
+    // the objects being formatted on this thread
+    static FORMATTING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Defines `inspect` for a Ruby class wrapping a `T`, showing the data as
+/// formatted by `Debug` after the class name: `#<Thing {:?}>`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_inspect<T: Debug + 'static>(klass: Value) {
+    let func = inspect::<T> as extern "C" fn(Value) -> Value;
+    define_method(klass, b"inspect\0", func as CallbackPtr, 0);
+}
+
+/// Defines `to_s` for a Ruby class wrapping a `T`, returning the data as
+/// formatted by `Display`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_to_s<T: Display + 'static>(klass: Value) {
+    let func = to_s::<T> as extern "C" fn(Value) -> Value;
+    define_method(klass, b"to_s\0", func as CallbackPtr, 0);
+}
+
+extern "C" fn inspect<T: Debug + 'static>(object: Value) -> Value {
+    format(object, |data: &T| {
+        format!("#<{} {:?}>", class_name(object), data)
+    })
+}
+
+extern "C" fn to_s<T: Display + 'static>(object: Value) -> Value {
+    format(object, |data: &T| data.to_string())
+}
+
+fn format<T: 'static, F: FnOnce(&T) -> String>(object: Value, f: F) -> Value {
+    let mut state = 0;
+    let value = call(|| {
+        let string = match Formatting::enter(object) {
+            Some(formatting) => {
+                let result = protect(|| with_ref(object, f));
+                drop(formatting);
+                match result {
+                    Ok(Ok(string)) => string,
+                    Ok(Err(WrapError::Empty)) => format!("#<{} (empty)>", class_name(object)),
+                    Ok(Err(WrapError::AlreadyBorrowed)) => {
+                        format!("#<{} (borrowed)>", class_name(object))
+                    }
+                    Ok(Err(error)) => return Err(error.into()),
+                    Err(tag) => {
+                        state = tag;
+                        return Ok(Value {
+                            value: Nil as usize,
+                        });
+                    }
+                }
+            }
+            None => format!("#<{} ...>", class_name(object)),
+        };
+        Ok(unsafe { rb_utf8_str_new(string.as_ptr() as *const c_char, string.len() as c_long) })
+    });
+    if state != 0 {
+        jump_tag(state);
+    }
+    value
+}
+
+// Marks an object as being formatted until dropped.
+struct Formatting(usize);
+
+impl Formatting {
+    // Returns `None` if the object is already being formatted.
+    fn enter(object: Value) -> Option<Formatting> {
+        FORMATTING.with(|formatting| {
+            let mut formatting = formatting.borrow_mut();
+            if formatting.contains(&object.value) {
+                return None;
+            }
+            formatting.push(object.value);
+            Some(Formatting(object.value))
+        })
+    }
+}
+
+impl Drop for Formatting {
+    fn drop(&mut self) {
+        FORMATTING.with(|formatting| formatting.borrow_mut().retain(|&object| object != self.0));
+    }
+}
@@ -2,7 +2,14 @@
 //! what Ruby's `Data_Wrap_Struct` macro does. That is to say, you can store a
 //! pointer to a Rust `Box<T>` inside a Ruby object and get it back out again.
 //!
-//! Any heap-allocated struct, enum, or whatever should work.
+//! Any heap-allocated struct, enum, or whatever should work. Each object
+//! remembers the Rust type it was created for, so asking for the wrong type
+//! gets you a `WrapError::TypeMismatch` rather than garbage. `WrapError`s can
+//! be raised as the matching Ruby exception with `WrapError::raise`.
+//!
+//! Frozen objects stay frozen: changing (or mutably borrowing) the data of a
+//! frozen object fails with `WrapError::Frozen`. Objects whose data is only an
+//! internal cache can opt out with `ignore_frozen`.
 //!
 //! ## Example
 //!
@@ -43,21 +50,226 @@
 //!     // create a new instance of the class
 //!     let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
 //!
+//!     // peek at your value without taking it out of the ruby object
+//!     let val = ruby_wrap_data::with_ref(thing, |data: &MyValue| data.val);
+//!     assert_eq!(val, Ok(1));
+//!
 //!     // get your value from the ruby object
-//!     // note: once remove() is called, the pointer in your ruby object is NULL
-//!     let data: Option<Box<MyValue>> = ruby_wrap_data::remove(thing);
-//!     assert!(data.is_some());
+//!     // note: once remove() is called, your ruby object is empty
+//!     let data: Box<MyValue> = ruby_wrap_data::remove(thing).unwrap();
+//!     assert_eq!(data.val, 1);
 //!
-//!     // if you try to remove it again, you get None
-//!     let data: Option<Box<MyValue>> = ruby_wrap_data::remove(thing);
-//!     assert!(data.is_none());
+//!     // if you try to remove it again, you get an error
+//!     let data = ruby_wrap_data::remove::<MyValue>(thing);
+//!     assert_eq!(data.err(), Some(ruby_wrap_data::WrapError::Empty));
 //!
 //!     // set a new value on the object
 //!     let new_data = Box::new(MyValue { val: 2 });
-//!     ruby_wrap_data::set(thing, new_data);
+//!     ruby_wrap_data::set(thing, new_data).unwrap();
+//! }
+//! ```
+//!
+//! ## Typed data
+//!
+//! `wrap` uses Ruby's untyped `rb_data_object_wrap`, which modern Rubies
+//! deprecate and which leaves Ruby itself (and any C code handed your objects)
+//! no way to tell wrapped types apart. For anything beyond a toy, describe each
+//! wrapped type with a `DataType` and use `wrap_typed`, `remove_typed`, and
+//! `set_typed` instead. Those also check (via `rb_typeddata_is_kind_of`) that
+//! Ruby agrees the object holds a `T`.
+//!
+//! ```rust,ignore
+//! use ruby_wrap_data::{DataType, TypedData};
+//!
+//! static MY_VALUE_TYPE: DataType<MyValue> = DataType::new("MyValue\0");
+//!
+//! impl TypedData for MyValue {
+//!     fn data_type() -> &'static DataType<MyValue> {
+//!         &MY_VALUE_TYPE
+//!     }
+//! }
+//!
+//! fn alloc(klass: Value) -> Value {
+//!     ruby_wrap_data::wrap_typed(klass, Some(Box::new(MyValue { val: 1 })))
+//! }
+//! ```
+//!
+//! `wrap`, `wrap_typed`, `set`, `remove`, `replace`, and `take` each have a
+//! `_value` counterpart (`wrap_value`, `wrap_typed_value`, and so on) dealing
+//! in the `T` itself rather than a `Box<T>`. The data is kept inline with this
+//! crate's own bookkeeping either way, so these save allocating (and freeing)
+//! a box each time. Derived classes allocate their objects this way.
+//!
+//! Typed data also decides when it is dropped. By default, Ruby defers the
+//! drop to its finalizer phase, after the GC is done, so `Drop` impls that
+//! close sockets or flush files are safe; types whose `Drop` only frees memory
+//! can use `DataType::free_immediately` to be dropped during sweep instead.
+//! Types that are slow to drop (huge maps, deep trees) can use
+//! `DataType::drop_in_background` to be dropped on a background thread, off
+//! the GC's clock; `flush_background_drops` waits for that thread to catch up.
+//!
+//! On Ruby 3.3 and up you can enable the `embed` feature and build a type's
+//! descriptor with `DataType::embeddable` to have Ruby keep the data inside the
+//! object itself, where it fits, saving an allocation per object.
+//!
+//! ## Holding Ruby values
+//!
+//! If your data holds on to Ruby `Value`s, Ruby has to be told about them or
+//! it will happily collect them. Implement `Mark` for your type and use
+//! `wrap_marked` (or `DataType::mark` for typed data):
+//!
+//! ```rust,ignore
+//! use ruby_wrap_data::Mark;
+//!
+//! struct Node {
+//!     name: Value,
+//!     children: Vec<Value>,
+//! }
+//!
+//! impl Mark for Node {
+//!     fn mark(&self) {
+//!         self.name.mark();
+//!         self.children.mark();
+//!     }
+//! }
+//!
+//! fn alloc(klass: Value) -> Value {
+//!     ruby_wrap_data::wrap_marked(klass, Some(Box::new(Node::new())))
+//! }
+//! ```
+//!
+//! ### Compaction
+//!
+//! Values marked with `Mark::mark` are pinned, so `GC.compact` will never move
+//! them. On Ruby 2.7 and up you can enable the `compact` feature, implement
+//! `Compact` for your type, and use `DataType::compact` instead of
+//! `DataType::mark`; Ruby is then free to move the values, and calls back
+//! into `Compact::compact` so you can update them with their new locations.
+//! Untyped data is always pinned.
+//!
+//! ## Memory usage
+//!
+//! Typed data tells Ruby how big the wrapped `T` is, so it shows up in
+//! `ObjectSpace.memsize_of` and memory profilers. If your type owns heap
+//! memory, implement `MemSize` and call `DataType::memsize` to have that
+//! counted as well:
+//!
+//! ```rust,ignore
+//! impl MemSize for Index {
+//!     fn memsize(&self) -> usize {
+//!         mem::size_of::<Index>() + self.records.heap_size()
+//!     }
+//! }
+//!
+//! static INDEX_TYPE: DataType<Index> = DataType::new("Index\0").memsize();
+//! ```
+//!
+//! ## Deriving classes
+//!
+//! With the `derive` feature enabled, `#[derive(RubyWrap)]` writes the
+//! `DataType`, the alloc function, and the class definition for you. The
+//! type must implement `Default`, which is what new instances start out as.
+//!
+//! ```rust,ignore
+//! #[macro_use]
+//! extern crate ruby_wrap_data;
+//!
+//! use ruby_wrap_data::RubyWrap;
+//!
+//! #[derive(Default, RubyWrap)]
+//! #[ruby(class = "Geometry::Point", free_immediately)]
+//! struct Point {
+//!     x: f64,
+//!     y: f64,
 //! }
+//!
+//! // defines Geometry (if need be) and Geometry::Point
+//! let klass = Point::define_class();
 //! ```
 //!
+//! The `ruby` attribute also accepts `mark`, `compact`, `memsize`, and
+//! `drop_in_background`, which call the `DataType` methods of the same names.
+//! Instances of derived classes can't be copied with `dup` or `clone` (which
+//! raise a `TypeError`) unless the type implements `Clone` and asks for it
+//! with `clone`; other classes can get the same behaviour from `define_clone`
+//! or `forbid_clone`.
+//!
+//! Wrapped objects print like any other (`#<Geometry::Point:0x...>`) unless
+//! given `inspect` and `to_s` methods showing the data: derive with
+//! `inspect` (for types implementing `Debug`) or `to_s` (for `Display`), or
+//! call `define_inspect` and `define_to_s` on any class.
+//!
+//! Similarly, objects are only equal to themselves unless given `==` (derive
+//! with `eq`, or call `define_eq`, for `PartialEq` types) or `eql?` and
+//! `hash` (`hash` or `define_hash`, for `Eq + Hash` types), which compare the
+//! data with that of other objects wrapping the same type. The latter makes
+//! instances holding equal data the same Hash key.
+//!
+//! `define_cmp` (or deriving with `cmp`) gives `PartialOrd` types `<=>` and
+//! includes `Comparable`, so `sort`, `min`, `max`, and `between?` work too.
+//!
+//! Collections can be made `Enumerable` with `define_each` (or `each`), for
+//! types whose references iterate over items implementing `IntoValue`. `each`
+//! yields each item in turn, keeping the data borrowed until it's done, and
+//! returns an `Enumerator` when called without a block.
+//!
+//! Types implementing the `std::ops` traits can have the matching Ruby
+//! operators: `define_add`, `define_sub`, `define_mul`, and `define_div` (for
+//! `+`, `-`, `*`, and `/`) and `define_neg` (for `-@`) wrap the result as a
+//! new instance of the same class, and `define_index` and `define_index_mut`
+//! give `[]` and `[]=` for types implementing `TryIndex` and `TryIndexMut`
+//! (as `Vec`, `VecDeque`, `HashMap`, and `BTreeMap` do), returning `nil` or
+//! raising an `IndexError` for a missing element. Operands and elements are converted with `FromValue`,
+//! which every `TypedData + Clone` type implements. `define_coerce` lets
+//! numbers come first, as in `10 - money`, by converting them into the
+//! class with `From`.
+//!
+//! ## Shared data
+//!
+//! To reach the same data from Ruby and from Rust (say, a cache), wrap an
+//! `Rc<T>` or `Arc<T>` with `wrap_rc` or `wrap_arc`. The object owns one
+//! strong reference, dropped when Ruby frees it, and `get_rc`/`get_arc` hand
+//! out more without emptying the object. `wrap_rc_unique` and
+//! `wrap_arc_unique` go further and return the existing object when handed
+//! data that's already been wrapped, keeping `equal?` (and caches keyed by
+//! object) working. For typed data, wrap a newtype around the `Rc` or `Arc`
+//! and implement `TypedData` for that.
+//!
+//! ## Marshal
+//!
+//! With the `serde` feature, `define_marshal` gives a class `marshal_dump` and
+//! `marshal_load` methods, so instances wrapping a `T: Serialize +
+//! DeserializeOwned` work with `Marshal.dump` and `Marshal.load` (and so with
+//! caches and DRb). The data is encoded with bincode behind a format version
+//! byte.
+//!
+//! ## Releasing the GVL
+//!
+//! CPU-heavy work on wrapped data doesn't need Ruby's Global VM Lock. Give it
+//! to `without_gvl` (or `without_gvl_unblock`, which Ruby can interrupt) and
+//! other Ruby threads keep running while it goes:
+//!
+//! ```rust,ignore
+//! let pixels = ruby_wrap_data::without_gvl(itself, |image: &mut Image| image.decode());
+//! ```
+//!
+//! The data must be `Send`, and must not hold Ruby values for the GC to mark.
+//! Until the closure returns, any other access to the data fails with
+//! `WrapError::AlreadyBorrowed`, and the closure itself must not call into Ruby.
+//!
+//! Data that is `Sync` can go to `without_gvl_ref` instead, whose closure takes
+//! a `&T`. That only needs a shared borrow, so it works on frozen objects too,
+//! and other threads can still read the data meanwhile.
+//!
+//! ## Panics
+//!
+//! Panics must not unwind into Ruby. Every callback this crate installs
+//! catches them: a panic in an alloc function is raised as a Ruby
+//! `RuntimeError`, while a panic during GC (in `Drop`, `Mark`, `MemSize`, and
+//! so on) aborts the process with a message, since there is nothing safe left
+//! to do. Wrap your own method callbacks in `rescue_panic` to get the same
+//! treatment.
+//!
 //! ## Testing
 //!
 //! Assuming you're using rbenv (if not, sorry, you're on your own):
@@ -74,14 +286,87 @@
 //! RUBY=$(rbenv which ruby) cargo test
 //! ```
 
+#[cfg(feature = "serde")]
+extern crate bincode;
 extern crate ruby_sys;
+#[cfg(feature = "derive")]
+extern crate ruby_wrap_data_derive;
+#[cfg(feature = "serde")]
+extern crate serde;
+
+// lets the derive's generated `::ruby_wrap_data::...` paths resolve in tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as ruby_wrap_data;
 
 use ruby_sys::types::{c_void, CallbackPtr, RBasic, Value};
 
+use ruby_sys::value::RubySpecialConsts::{False, Nil};
+
+use std::any::TypeId;
+use std::cell::Cell;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{mem, ptr};
 
+mod background;
+mod borrow;
+mod class;
+mod cmp;
+#[cfg(feature = "compact")]
+mod compact;
+mod convert;
+mod copy;
+mod each;
+mod error;
+mod gc;
+mod gvl;
+mod inspect;
+#[cfg(feature = "serde")]
+mod marshal;
+mod memsize;
+mod method;
+mod ops;
+mod shared;
+mod typed;
+mod unwind;
+
+pub use background::flush_background_drops;
+pub use borrow::{get_mut, get_ref, with_mut, with_ref, DataRef, DataRefMut};
+pub use class::RubyWrap;
+pub use cmp::{define_cmp, define_eq, define_hash};
+#[cfg(feature = "compact")]
+pub use compact::Compact;
+pub use convert::{FromValue, IntoValue};
+pub use copy::{define_clone, forbid_clone};
+pub use each::define_each;
+pub use error::WrapError;
+pub use gc::Mark;
+pub use gvl::{without_gvl, without_gvl_ref, without_gvl_unblock};
+pub use inspect::{define_inspect, define_to_s};
+#[cfg(feature = "serde")]
+pub use marshal::define_marshal;
+pub use memsize::MemSize;
+pub use ops::{define_add, define_coerce, define_div, define_index, define_index_mut};
+pub use ops::{define_mul, define_neg, define_sub, TryIndex, TryIndexMut};
+#[cfg(feature = "derive")]
+pub use ruby_wrap_data_derive::RubyWrap;
+pub use shared::{get_arc, get_rc, wrap_arc, wrap_arc_unique, wrap_rc, wrap_rc_unique};
+pub use typed::{remove_typed, set_typed, wrap_typed, wrap_typed_value};
+pub use typed::{DataType, TypedData};
+pub use unwind::rescue_panic;
+
+// for the code `#[derive(RubyWrap)]` generates, since the crates using it
+// needn't depend on ruby-sys themselves
+#[doc(hidden)]
+pub use ruby_sys::types::Value as __Value;
+
 extern "C" {
     fn rb_define_alloc_func(klass: Value, func: CallbackPtr);
+    fn rb_intern(name: *const c_char) -> usize;
+    fn rb_include_module(klass: Value, module: Value);
+    fn rb_ivar_set(object: Value, id: usize, value: Value) -> Value;
+    fn rb_attr_get(object: Value, id: usize) -> Value;
+    fn rb_class_get_superclass(klass: Value) -> Value;
     fn rb_data_object_wrap(
         klass: Value,
         datap: *mut c_void,
@@ -90,6 +375,8 @@ extern "C" {
     ) -> Value;
 }
 
+// Typed data objects (`RTypedData`) keep their data pointer at the same
+// offset, so this layout works for both kinds of object.
 #[repr(C)]
 struct RData {
     basic: RBasic,
@@ -98,6 +385,41 @@ struct RData {
     pub data: *mut c_void,
 }
 
+#[repr(C)]
+struct RTypedData {
+    basic: RBasic,
+    data_type: *const c_void,
+    // 1 for typed data, or 3 if the data is embedded in the object (from
+    // `data` on); where `RData` keeps `dfree` otherwise
+    typed_flag: usize,
+    data: *mut c_void,
+}
+
+const TYPED_DATA_EMBEDDED: usize = 2;
+
+const T_STRING: usize = 0x05;
+const T_DATA: usize = 0x0c;
+const T_MASK: usize = 0x1f;
+const FL_FREEZE: usize = 1 << 11;
+const IMMEDIATE_MASK: usize = 0x07;
+
+// Every object wrapped by this crate points at one of these, even while it
+// is empty, so the type it was created for can always be checked before the
+// data is touched.
+#[repr(C)]
+struct Slot<T> {
+    type_id: TypeId,
+    // like `RefCell`: the number of `DataRef`s, or -1 for a `DataRefMut`, or
+    // `gvl::UNLOCKED` while `without_gvl` has the data
+    borrow: Cell<isize>,
+    // set by `ignore_frozen`
+    ignore_frozen: Cell<bool>,
+    // the fields up to here are laid out the same whatever `T` is, so `free`
+    // and `ignore_frozen` can find them through a `Slot<()>`
+    drop: DropSlot<()>,
+    data: Option<T>,
+}
+
 /// Defines an 'alloc' function for a Ruby class. Such a function should
 /// build your initial data and return the result of calling
 /// `wrap(klass, data)`.
@@ -106,32 +428,87 @@ struct RData {
 ///
 /// * `klass` - a Ruby Class
 /// * `alloc` - a function taking a Ruby Value and returning a Ruby Value
+///
+/// # Notes
+///
+/// If `alloc` panics, the panic is raised in Ruby as a `RuntimeError`.
 pub fn define_alloc_func(klass: Value, alloc: fn(Value) -> Value) {
-    unsafe { rb_define_alloc_func(klass, alloc as CallbackPtr) };
+    let id = unsafe { rb_intern(b"__ruby_wrap_data_alloc__\0".as_ptr() as *const c_char) };
+    ALLOC_FUNC_ID.store(id, Ordering::Relaxed);
+    unsafe { rb_ivar_set(klass, id, (alloc as usize).into_value()) };
+    unsafe { rb_define_alloc_func(klass, alloc_func as CallbackPtr) };
+}
+
+// Ruby only passes the class to an alloc function, so every class shares
+// `alloc_func`, which finds the real one in an instance variable of the
+// class. Its name has no `@`, which hides it from Ruby code.
+static ALLOC_FUNC_ID: AtomicUsize = AtomicUsize::new(0);
+
+type AllocFunc = fn(Value) -> Value;
+
+extern "C" fn alloc_func(klass: Value) -> Value {
+    rescue_panic(|| find_alloc_func(klass).expect("no alloc function defined")(klass))
+}
+
+// Subclasses inherit their alloc function, so this walks up from `klass`
+// (usually finding it straight away). Included modules along the way never
+// have one.
+fn find_alloc_func(klass: Value) -> Option<AllocFunc> {
+    let id = ALLOC_FUNC_ID.load(Ordering::Relaxed);
+    let mut class = klass;
+    while class.value != False as usize && class.value != Nil as usize {
+        if let Some(alloc) = usize::from_value(unsafe { rb_attr_get(class, id) }) {
+            return Some(unsafe { mem::transmute::<usize, AllocFunc>(alloc) });
+        }
+        class = unsafe { rb_class_get_superclass(class) };
+    }
+    None
 }
 
 /// Creates a new instance of the given class, wrapping the given
-/// heap-allocated data type.
+/// heap-allocated data type. Once the object is garbage, the data is dropped
+/// in Ruby's finalizer phase, after the GC is done.
 ///
 /// # Arguments
 ///
 /// * `klass` - a Ruby Class
 /// * `data`  - an `Option<Box<T>>` - the data you wish to embed in the Ruby object or None
-pub fn wrap<T>(klass: Value, data: Option<Box<T>>) -> Value {
-    let datap = if data.is_some() {
-        Box::into_raw(data.unwrap()) as *mut c_void
-    } else {
-        ptr::null_mut()
-    };
-    unsafe { rb_data_object_wrap(klass, datap, None, Some(free::<T>)) }
+pub fn wrap<T: 'static>(klass: Value, data: Option<Box<T>>) -> Value {
+    let datap = new_slot(data.map(|data| *data), drop_slot::<T>);
+    unsafe { rb_data_object_wrap(klass, datap, None, Some(free)) }
+}
+
+/// Like `wrap`, but takes the data itself rather than a box, saving an
+/// allocation.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+/// * `data`  - a `T` - the data you wish to embed in the Ruby object
+pub fn wrap_value<T: 'static>(klass: Value, data: T) -> Value {
+    let datap = new_slot(Some(data), drop_slot::<T>);
+    unsafe { rb_data_object_wrap(klass, datap, None, Some(free)) }
 }
 
-/// Removes and returns the wrapped data from the given Ruby object.
-/// Returns None if the data is currently NULL.
+/// Creates a new instance of the given class, wrapping the given
+/// heap-allocated data type and marking the Ruby values it holds whenever
+/// the GC runs.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+/// * `data`  - an `Option<Box<T>>` - the data you wish to embed in the Ruby object or None
+pub fn wrap_marked<T: Mark + 'static>(klass: Value, data: Option<Box<T>>) -> Value {
+    let datap = new_slot(data.map(|data| *data), drop_slot::<T>);
+    unsafe { rb_data_object_wrap(klass, datap, Some(gc::mark::<T>), Some(free)) }
+}
+
+/// Removes and returns the wrapped data from the given Ruby object, leaving
+/// it empty. Returns `WrapError::Empty` if it is already empty.
 ///
 /// # Arguments
 ///
-/// * `object` - a Ruby object
+/// * `object` - a Ruby object created by this crate
 ///
 /// # Notes
 ///
@@ -139,66 +516,300 @@ pub fn wrap<T>(klass: Value, data: Option<Box<T>>) -> Value {
 /// cannot be inferred:
 ///
 /// ```rust,ignore
-/// let data: Option<Box<MyValue>> = ruby_wrap_data::remove(thing);
+/// let data: Box<MyValue> = ruby_wrap_data::remove(thing).unwrap();
 /// ```
 ///
-/// Also note, if you wish to peek at the data without removing it,
-/// you will need to put it back using `set`, like this:
+/// Also note, if you only wish to peek at the data, borrow it in place
+/// with `with_ref` or `with_mut` instead:
 ///
 /// ```rust,ignore
-/// let data: Option<Box<MyValue>> = ruby_wrap_data::remove(thing);
-/// // do something
-/// ruby_wrap_data::set(thing, data.unwrap());
+/// let val = ruby_wrap_data::with_ref(thing, |data: &MyValue| data.val);
 /// ```
-pub fn remove<T>(object: Value) -> Option<Box<T>> {
-    let rdata = rdata(object);
-    let datap = unsafe { (*rdata).data as *mut T };
-    if datap.is_null() {
-        None
-    } else {
-        set_none(object);
-        Some(unsafe { Box::from_raw(datap) })
-    }
+pub fn remove<T: 'static>(object: Value) -> Result<Box<T>, WrapError> {
+    remove_value(object).map(Box::new)
+}
+
+/// Like `remove`, but returns the data itself rather than a box, saving an
+/// allocation.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+pub fn remove_value<T: 'static>(object: Value) -> Result<T, WrapError> {
+    let slot = slot_mut::<T>(object)?;
+    let data = unsafe { (*slot).data.take() };
+    data.ok_or(WrapError::Empty)
 }
 
-/// Sets the wrapped data on the given Ruby object.
+/// Sets the wrapped data on the given Ruby object, dropping any data it
+/// already held.
 ///
 /// # Arguments
 ///
-/// * `object` - a Ruby object
+/// * `object` - a Ruby object created by this crate
 /// * `data`   - a `Box<T>` - the data you wish to embed in the Ruby object
-pub fn set<T>(object: Value, data: Box<T>) {
-    let rdata = rdata(object);
-    let datap = Box::into_raw(data) as *mut c_void;
-    unsafe { (*rdata).data = datap };
+// the data is moved out of the box; `set_value` saves making one
+#[allow(clippy::boxed_local)]
+pub fn set<T: 'static>(object: Value, data: Box<T>) -> Result<(), WrapError> {
+    set_value(object, *data)
+}
+
+/// Like `set`, but takes the data itself rather than a box, saving an
+/// allocation.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+/// * `data`   - a `T` - the data you wish to embed in the Ruby object
+pub fn set_value<T: 'static>(object: Value, data: T) -> Result<(), WrapError> {
+    let slot = slot_mut::<T>(object)?;
+    unsafe { (*slot).data = Some(data) };
+    Ok(())
+}
+
+/// Sets the wrapped data on the given Ruby object, returning whatever data it
+/// held before (or None if it was empty), like `std::mem::replace`.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+/// * `data`   - a `Box<T>` - the data you wish to embed in the Ruby object
+// the data is moved out of the box; `replace_value` saves making one
+#[allow(clippy::boxed_local)]
+pub fn replace<T: 'static>(object: Value, data: Box<T>) -> Result<Option<Box<T>>, WrapError> {
+    let old = replace_value(object, *data)?;
+    Ok(old.map(Box::new))
+}
+
+/// Like `replace`, but takes and returns the data itself rather than boxes,
+/// saving an allocation each way.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+/// * `data`   - a `T` - the data you wish to embed in the Ruby object
+pub fn replace_value<T: 'static>(object: Value, data: T) -> Result<Option<T>, WrapError> {
+    let slot = slot_mut::<T>(object)?;
+    Ok(unsafe { (*slot).data.replace(data) })
 }
 
-extern "C" fn free<T>(data: *mut c_void) {
+/// Takes the wrapped data from the given Ruby object, leaving `T::default()`
+/// in its place, like `std::mem::take`. Returns `WrapError::Empty` (and
+/// leaves the object empty) if there was nothing to take.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+pub fn take<T: Default + 'static>(object: Value) -> Result<Box<T>, WrapError> {
+    take_value(object).map(Box::new)
+}
+
+/// Like `take`, but returns the data itself rather than a box, saving an
+/// allocation.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+pub fn take_value<T: Default + 'static>(object: Value) -> Result<T, WrapError> {
+    let slot = slot_mut::<T>(object)?;
+    match unsafe { (*slot).data.as_mut() } {
+        Some(data) => Ok(mem::take(data)),
+        None => Err(WrapError::Empty),
+    }
+}
+
+/// Swaps the wrapped data of two Ruby objects, like `std::mem::swap`. Either
+/// object may be empty. If either object cannot be changed, both are left
+/// untouched.
+///
+/// # Arguments
+///
+/// * `a` - a Ruby object created by this crate
+/// * `b` - another Ruby object created by this crate
+pub fn swap<T: 'static>(a: Value, b: Value) -> Result<(), WrapError> {
+    let a = slot_mut::<T>(a)?;
+    let b = slot_mut::<T>(b)?;
+    // `a` and `b` may well be the same object, which rules out `mem::swap`
+    unsafe { ptr::swap(&mut (*a).data, &mut (*b).data) };
+    Ok(())
+}
+
+/// Lets the data of the given Ruby object be changed even after the object
+/// is frozen, for objects whose data is only an internal cache that Ruby code
+/// can't observe. Without this, `set`, `remove`, `get_mut` and friends fail
+/// with `WrapError::Frozen` on a frozen object.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created by this crate
+pub fn ignore_frozen(object: Value) -> Result<(), WrapError> {
+    let rdata = rdata(object)?;
+    let slot = unsafe { data_ptr(rdata) as *const Slot<()> };
+    unsafe { (*slot).ignore_frozen.set(true) };
+    Ok(())
+}
+
+// The one free function for every object this crate creates, whatever it
+// wraps, which is how `rdata` recognises them.
+extern "C" fn free(data: *mut c_void) {
+    let slot = data as *mut Slot<()>;
+    let drop_slot = unsafe { (*slot).drop };
+    unwind::abort_on_panic("free", || unsafe { drop_slot(slot) });
+}
+
+unsafe fn drop_slot<T>(slot: *mut Slot<T>) {
     // memory is freed when the box goes out of the scope
-    let datap = data as *mut T;
-    unsafe { Box::from_raw(datap) };
+    drop(Box::from_raw(slot));
+}
+
+// For slots embedded in their object, whose memory is Ruby's to free.
+#[cfg(feature = "embed")]
+unsafe fn drop_slot_in_place<T>(slot: *mut Slot<T>) {
+    ptr::drop_in_place(slot);
+}
+
+// Drops a slot (and its data) once Ruby frees its object; taking the slot
+// as a `Slot<T>` means it can't be paired with a slot of another type.
+type DropSlot<T> = unsafe fn(*mut Slot<T>);
+
+fn new_slot<T: 'static>(data: Option<T>, drop: DropSlot<T>) -> *mut c_void {
+    Box::into_raw(Box::new(make_slot(data, drop))) as *mut c_void
+}
+
+fn make_slot<T: 'static>(data: Option<T>, drop: DropSlot<T>) -> Slot<T> {
+    gvl::check();
+    // `free` only knows the slot as a `Slot<()>`, and pointers are passed
+    // alike whatever they point to
+    let drop = unsafe { mem::transmute::<DropSlot<T>, DropSlot<()>>(drop) };
+    Slot {
+        type_id: TypeId::of::<T>(),
+        borrow: Cell::new(0),
+        ignore_frozen: Cell::new(false),
+        drop,
+        data,
+    }
 }
 
-fn set_none(object: Value) {
-    let rdata = rdata(object);
-    unsafe { (*rdata).data = ptr::null_mut() };
+// Returns where the given data object keeps its data: inline for embedded
+// typed data, behind the data pointer otherwise.
+unsafe fn data_ptr(rdata: *const RData) -> *mut c_void {
+    let typed = rdata as *mut RTypedData;
+    if is_typed(rdata) && (*typed).typed_flag & TYPED_DATA_EMBEDDED != 0 {
+        &mut (*typed).data as *mut *mut c_void as *mut c_void
+    } else {
+        (*rdata).data
+    }
+}
+
+// Returns the object's slot, provided it was created to hold a `T`.
+fn slot<T: 'static>(object: Value) -> Result<*mut Slot<T>, WrapError> {
+    let rdata = rdata(object)?;
+    let slot = unsafe { data_ptr(rdata) as *mut Slot<T> };
+    if slot.is_null() {
+        Err(WrapError::NotData)
+    } else if unsafe { (*slot).type_id } != TypeId::of::<T>() {
+        Err(WrapError::TypeMismatch)
+    } else {
+        Ok(slot)
+    }
+}
+
+// Like `slot`, but for changing what the slot holds, which can't be done
+// while the object is frozen or the data is borrowed.
+fn slot_mut<T: 'static>(object: Value) -> Result<*mut Slot<T>, WrapError> {
+    let slot = slot::<T>(object)?;
+    if is_frozen(object) && unsafe { !(*slot).ignore_frozen.get() } {
+        Err(WrapError::Frozen)
+    } else if unsafe { (*slot).borrow.get() } != 0 {
+        Err(WrapError::AlreadyBorrowed)
+    } else {
+        Ok(slot)
+    }
 }
 
-fn rdata(object: Value) -> *mut RData {
-    unsafe { mem::transmute(object) }
+// Returns the object's `RData`, provided it is a data object (typed or not)
+// created by this crate. Anything else, from `nil` to another extension's
+// data, has no slot to look at.
+fn rdata(object: Value) -> Result<*mut RData, WrapError> {
+    gvl::check();
+    if is_special_const(object) || builtin_type(object) != T_DATA {
+        return Err(WrapError::NotData);
+    }
+    let rdata = object.value as *mut RData;
+    let (_, dfree) = data_funcs(rdata);
+    let ours: extern "C" fn(*mut c_void) = free;
+    if dfree.map(|f| f as usize) == Some(ours as usize) {
+        Ok(rdata)
+    } else {
+        Err(WrapError::NotData)
+    }
+}
+
+type DataFunc = Option<extern "C" fn(*mut c_void)>;
+
+// Returns the mark and free functions Ruby has for the given data object.
+fn data_funcs(rdata: *const RData) -> (DataFunc, DataFunc) {
+    let typed = rdata as *const RTypedData;
+    unsafe {
+        if is_typed(rdata) {
+            typed::data_funcs((*typed).data_type)
+        } else {
+            ((*rdata).dmark, (*rdata).dfree)
+        }
+    }
+}
+
+// Ruby's `RTYPEDDATA_P`; no `dfree` function lives at address 1 or 3.
+unsafe fn is_typed(rdata: *const RData) -> bool {
+    let flag = (*(rdata as *const RTypedData)).typed_flag;
+    flag == 1 || flag == 1 | TYPED_DATA_EMBEDDED
+}
+
+// Immediates (Fixnums, Symbols, Floats, true) and `false`/`nil` aren't
+// pointers at all.
+fn is_special_const(value: Value) -> bool {
+    value.value & IMMEDIATE_MASK != 0 || value.value & !(Nil as usize) == 0
+}
+
+fn builtin_type(value: Value) -> usize {
+    let basic = value.value as *const RBasic;
+    unsafe { (*basic).flags & T_MASK }
+}
+
+fn is_frozen(object: Value) -> bool {
+    let basic = object.value as *const RBasic;
+    unsafe { (*basic).flags & FL_FREEZE != 0 }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use typed::FREE_IMMEDIATELY;
+
+    use ruby_sys::{
+        class::{rb_class_new_instance, rb_define_class},
+        rb_cObject,
+        types::Value,
+        value::RubySpecialConsts::{Nil, True},
+        vm,
+    };
+
+    #[cfg(feature = "serde")]
+    use serde::{Deserialize, Serialize};
 
-    use ruby_sys::{vm, class::{rb_class_new_instance, rb_define_class}, rb_cObject, types::Value,
-                   value::RubySpecialConsts::Nil};
+    use std::ffi::{CStr, CString};
+    use std::fmt;
+    use std::ops;
+    use std::os::raw::{c_char, c_int, c_long};
+    use std::rc::Rc;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Arc;
+    use std::sync::{Mutex, OnceLock};
+    use std::{panic, thread};
 
-    use std::ffi::CString;
-    use std::sync::{Once, ONCE_INIT};
+    type Job = Box<dyn FnOnce() + Send>;
 
-    static RUBY_INIT: Once = ONCE_INIT;
+    static RUBY: OnceLock<Mutex<Sender<Job>>> = OnceLock::new();
 
     const RB_NIL: Value = Value {
         value: Nil as usize,
@@ -218,50 +829,1283 @@ mod tests {
         wrap::<Option<Box<MyValue>>>(klass, None)
     }
 
-    fn ruby_init() {
-        println!("h");
-        RUBY_INIT.call_once(|| {
-            unsafe { vm::ruby_init() };
-            println!("here");
+    // Ruby may only be driven from the thread that started it, and the test
+    // runner uses a thread per test, so every test body is shipped over to a
+    // single long-lived Ruby thread and run there.
+    fn with_ruby<F: FnOnce() + Send + 'static>(test: F) {
+        let sender = RUBY.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            thread::spawn(move || {
+                unsafe { vm::ruby_init() };
+                for job in receiver {
+                    job();
+                }
+            });
+            Mutex::new(sender)
         });
+        let (done, finished) = mpsc::channel();
+        let job: Job = Box::new(move || {
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(test));
+            done.send(result).unwrap();
+        });
+        sender.lock().unwrap().send(job).unwrap();
+        if let Err(cause) = finished.recv().unwrap() {
+            panic::resume_unwind(cause);
+        }
     }
 
     #[test]
     fn it_works() {
-        ruby_init();
+        with_ruby(|| {
+            // create our class
+            let name = CString::new("Thing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+
+            // set up our alloc function and create the object
+            define_alloc_func(klass, alloc);
+            let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            // asking for the wrong type is an error, and leaves the data alone
+            assert_eq!(
+                remove::<Option<Box<MyValue>>>(thing),
+                Err(WrapError::TypeMismatch)
+            );
+            assert_eq!(
+                set(thing, Box::new(MyTypedValue { val: 1 })),
+                Err(WrapError::TypeMismatch)
+            );
+
+            // the data matches what we put in
+            let data: Box<MyValue> = remove(thing).unwrap();
+            assert_eq!(*data, MyValue { val: 1 });
+
+            // now it's empty
+            assert_eq!(remove::<MyValue>(thing), Err(WrapError::Empty));
+
+            // set new data
+            let new_data = Box::new(MyValue { val: 2 });
+            set(thing, new_data).unwrap();
+
+            // looks right
+            let data: Box<MyValue> = remove(thing).unwrap();
+            assert_eq!(*data, MyValue { val: 2 });
+
+            // create our class
+            let name = CString::new("Thing2").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+
+            // set up our alloc function and create the object
+            define_alloc_func(klass, alloc_using_none);
+            let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            // nothing was put in
+            assert_eq!(remove::<Option<Box<MyValue>>>(thing), Err(WrapError::Empty));
+        });
+    }
+
+    extern "C" {
+        static rb_eRuntimeError: Value;
+        fn rb_gc();
+        fn rb_obj_dup(object: Value) -> Value;
+        fn rb_utf8_str_new(ptr: *const c_char, len: c_long) -> Value;
+    }
+
+    // Calls `f`, returning the exception if it raises one.
+    fn protect<F: FnOnce() -> Value>(f: F) -> Result<Value, Value> {
+        extern "C" {
+            fn rb_protect(
+                func: extern "C" fn(Value) -> Value,
+                arg: Value,
+                state: *mut c_int,
+            ) -> Value;
+            fn rb_errinfo() -> Value;
+            fn rb_set_errinfo(error: Value);
+        }
+
+        extern "C" fn call<F: FnOnce() -> Value>(arg: Value) -> Value {
+            let f = unsafe { &mut *(arg.value as *mut Option<F>) };
+            f.take().unwrap()()
+        }
+
+        let mut f = Some(f);
+        let arg = Value {
+            value: &mut f as *mut Option<F> as usize,
+        };
+        let mut state = 0;
+        let result = unsafe { rb_protect(call::<F>, arg, &mut state) };
+        if state == 0 {
+            Ok(result)
+        } else {
+            let error = unsafe { rb_errinfo() };
+            unsafe { rb_set_errinfo(RB_NIL) };
+            Err(error)
+        }
+    }
+
+    fn class_of(object: Value) -> Value {
+        extern "C" {
+            fn rb_obj_class(object: Value) -> Value;
+        }
+        unsafe { rb_obj_class(object) }
+    }
+
+    fn str_new(s: &str) -> Value {
+        unsafe { rb_utf8_str_new(s.as_ptr() as *const c_char, s.len() as c_long) }
+    }
+
+    fn str_value(mut string: Value) -> String {
+        extern "C" {
+            fn rb_string_value_cstr(string: *mut Value) -> *const c_char;
+        }
+        let s = unsafe { CStr::from_ptr(rb_string_value_cstr(&mut string)) };
+        s.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn it_rejects_objects_it_did_not_create() {
+        extern "C" {
+            fn rb_thread_current() -> Value;
+        }
+
+        with_ruby(|| {
+            let fixnum = Value { value: 0x03 };
+            let string = str_new("foo");
+            // a typed data object, but not one of ours
+            let thread = unsafe { rb_thread_current() };
+
+            for &object in &[RB_NIL, fixnum, string, thread] {
+                assert_eq!(remove::<MyValue>(object), Err(WrapError::NotData));
+                assert_eq!(
+                    set(object, Box::new(MyValue { val: 1 })),
+                    Err(WrapError::NotData)
+                );
+                assert!(unsafe { get_ref::<MyValue>(object) }.is_err());
+                assert_eq!(
+                    remove_typed::<MyTypedValue>(object),
+                    Err(WrapError::NotData)
+                );
+                assert_eq!(
+                    set_typed(object, Box::new(MyTypedValue { val: 1 })),
+                    Err(WrapError::NotData)
+                );
+            }
+        });
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct MyTypedValue {
+        pub val: u16,
+    }
+
+    static MY_TYPED_VALUE_TYPE: DataType<MyTypedValue> = DataType::new("MyTypedValue\0");
+
+    impl TypedData for MyTypedValue {
+        fn data_type() -> &'static DataType<MyTypedValue> {
+            &MY_TYPED_VALUE_TYPE
+        }
+    }
+
+    fn alloc_typed(klass: Value) -> Value {
+        wrap_typed(klass, Some(Box::new(MyTypedValue { val: 1 })))
+    }
+
+    #[test]
+    fn it_wraps_typed_data() {
+        with_ruby(|| {
+            let name = CString::new("TypedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc_typed);
+            let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            // the data matches what we put in
+            let data: Box<MyTypedValue> = remove_typed(thing).unwrap();
+            assert_eq!(*data, MyTypedValue { val: 1 });
+
+            // now it's empty
+            assert_eq!(remove_typed::<MyTypedValue>(thing), Err(WrapError::Empty));
+
+            // set new data and get it back
+            set_typed(thing, Box::new(MyTypedValue { val: 2 })).unwrap();
+            let data: Box<MyTypedValue> = remove_typed(thing).unwrap();
+            assert_eq!(*data, MyTypedValue { val: 2 });
+
+            // an untyped object holding the same type isn't of its data type
+            let untyped = wrap(klass, Some(Box::new(MyTypedValue { val: 3 })));
+            assert_eq!(
+                remove_typed::<MyTypedValue>(untyped),
+                Err(WrapError::TypeMismatch)
+            );
+        });
+    }
+
+    struct Immediate;
+
+    static IMMEDIATE_TYPE: DataType<Immediate> = DataType::new("Immediate\0").free_immediately();
+
+    impl TypedData for Immediate {
+        fn data_type() -> &'static DataType<Immediate> {
+            &IMMEDIATE_TYPE
+        }
+    }
+
+    // Ruby's `rb_data_type_t`, up to its flags
+    #[repr(C)]
+    struct RbDataType {
+        wrap_struct_name: *const c_char,
+        function: [*const c_void; 5],
+        parent: *const c_void,
+        data: *mut c_void,
+        flags: usize,
+    }
+
+    // The flags of the data type Ruby has for the given typed object.
+    fn typed_flags(object: Value) -> usize {
+        let typed = object.value as *const RTypedData;
+        unsafe {
+            assert_eq!((*typed).typed_flag, 1);
+            (*((*typed).data_type as *const RbDataType)).flags
+        }
+    }
+
+    #[test]
+    fn it_frees_immediately_on_request() {
+        with_ruby(|| {
+            let name = CString::new("ImmediateThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            let thing = wrap_typed(klass, Some(Box::new(Immediate)));
+            assert_eq!(typed_flags(thing) & FREE_IMMEDIATELY, FREE_IMMEDIATELY);
+
+            // the drop is deferred unless asked for
+            let thing = wrap_typed(klass, Some(Box::new(MyTypedValue { val: 1 })));
+            assert_eq!(typed_flags(thing) & FREE_IMMEDIATELY, 0);
+        });
+    }
+
+    #[cfg(feature = "embed")]
+    struct Embedded {
+        val: u32,
+    }
+
+    #[cfg(feature = "embed")]
+    static EMBEDDED_TYPE: DataType<Embedded> = DataType::new("Embedded\0").embeddable();
+
+    #[cfg(feature = "embed")]
+    impl TypedData for Embedded {
+        fn data_type() -> &'static DataType<Embedded> {
+            &EMBEDDED_TYPE
+        }
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn it_embeds_data_in_the_object() {
+        with_ruby(|| {
+            let name = CString::new("EmbeddedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            let thing = wrap_typed_value(klass, Embedded { val: 1 });
+            let typed = thing.value as *const RTypedData;
+            assert_eq!(unsafe { (*typed).typed_flag }, 1 | TYPED_DATA_EMBEDDED);
+            // the slot starts where the data pointer would otherwise be
+            let inline = unsafe { &(*typed).data } as *const *mut c_void as usize;
+            assert_eq!(slot::<Embedded>(thing).unwrap() as usize, inline);
+
+            with_mut(thing, |data: &mut Embedded| data.val = 2).unwrap();
+            assert_eq!(remove_value::<Embedded>(thing).unwrap().val, 2);
+            set_value(thing, Embedded { val: 3 }).unwrap();
+            assert_eq!(with_ref(thing, |data: &Embedded| data.val), Ok(3));
+        });
+    }
+
+    struct Holder {
+        values: Vec<Value>,
+    }
+
+    impl Mark for Holder {
+        fn mark(&self) {
+            self.values.mark();
+        }
+    }
+
+    fn alloc_marked(klass: Value) -> Value {
+        let values = vec![str_new("foo"), str_new("bar")];
+        wrap_marked(klass, Some(Box::new(Holder { values })))
+    }
+
+    #[test]
+    fn it_marks_wrapped_values() {
+        with_ruby(|| {
+            let name = CString::new("MarkedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc_marked);
+            let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            // the strings survive a full GC because the holder marks them
+            unsafe { rb_gc() };
+            let data: Box<Holder> = remove(thing).unwrap();
+            assert_eq!(data.values.len(), 2);
+            for value in data.values {
+                assert_eq!(builtin_type(value), T_STRING);
+            }
+        });
+    }
+
+    #[test]
+    fn it_borrows_in_place() {
+        with_ruby(|| {
+            let name = CString::new("BorrowedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc);
+            let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            // reading leaves the data in place
+            assert_eq!(with_ref(thing, |data: &MyValue| data.val), Ok(1));
+            assert_eq!(unsafe { get_ref::<MyValue>(thing) }.unwrap().val, 1);
+
+            // writing through the borrow is visible afterwards
+            with_mut(thing, |data: &mut MyValue| data.val = 2).unwrap();
+            unsafe { get_mut::<MyValue>(thing) }.unwrap().val += 1;
+            assert_eq!(with_ref(thing, |data: &MyValue| data.val), Ok(3));
+
+            // a Ruby exception out of the closure still releases the borrow
+            protect(|| {
+                with_mut(thing, |_: &mut MyValue| -> Value {
+                    WrapError::Empty.raise()
+                })
+                .unwrap()
+            })
+            .expect_err("raised");
+            protect(|| {
+                with_ref(thing, |_: &MyValue| -> Value { WrapError::Empty.raise() }).unwrap()
+            })
+            .expect_err("raised");
+            assert!(unsafe { get_mut::<MyValue>(thing) }.is_ok());
+
+            // borrowing as the wrong type is an error
+            assert!(unsafe { get_ref::<Holder>(thing) }.is_err());
+
+            // an empty object is never borrowed
+            let data: Box<MyValue> = remove(thing).unwrap();
+            assert_eq!(*data, MyValue { val: 3 });
+            assert_eq!(
+                with_ref(thing, |data: &MyValue| data.val),
+                Err(WrapError::Empty)
+            );
+            assert!(unsafe { get_mut::<MyValue>(thing) }.is_err());
+        });
+    }
+
+    #[test]
+    fn it_refuses_conflicting_borrows() {
+        with_ruby(|| {
+            let name = CString::new("ConflictedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc);
+            let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            // shared borrows stack, but nothing may change the data under them
+            let data = unsafe { get_ref::<MyValue>(thing) }.unwrap();
+            assert_eq!(unsafe { get_ref::<MyValue>(thing) }.unwrap().val, 1);
+            assert!(unsafe { get_mut::<MyValue>(thing) }.is_err());
+            assert_eq!(remove::<MyValue>(thing), Err(WrapError::AlreadyBorrowed));
+            drop(data);
+
+            // a mutable borrow excludes everything else
+            let data = unsafe { get_mut::<MyValue>(thing) }.unwrap();
+            assert!(unsafe { get_ref::<MyValue>(thing) }.is_err());
+            assert_eq!(
+                set(thing, Box::new(MyValue { val: 2 })),
+                Err(WrapError::AlreadyBorrowed)
+            );
+            drop(data);
+
+            assert_eq!(remove::<MyValue>(thing), Ok(Box::new(MyValue { val: 1 })));
+        });
+    }
+
+    #[test]
+    fn it_leaves_frozen_objects_alone() {
+        extern "C" {
+            fn rb_obj_freeze(object: Value) -> Value;
+        }
+
+        with_ruby(|| {
+            let name = CString::new("FrozenThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc);
+            let thing = unsafe { rb_obj_freeze(rb_class_new_instance(0, &RB_NIL, klass)) };
+
+            // reading is fine, changing is not
+            assert_eq!(with_ref(thing, |data: &MyValue| data.val), Ok(1));
+            assert_eq!(remove::<MyValue>(thing), Err(WrapError::Frozen));
+            assert_eq!(
+                set(thing, Box::new(MyValue { val: 2 })),
+                Err(WrapError::Frozen)
+            );
+            assert!(unsafe { get_mut::<MyValue>(thing) }.is_err());
+
+            // unless the object opts out
+            ignore_frozen(thing).unwrap();
+            with_mut(thing, |data: &mut MyValue| data.val = 2).unwrap();
+            assert_eq!(remove::<MyValue>(thing), Ok(Box::new(MyValue { val: 2 })));
+        });
+    }
+
+    #[test]
+    fn it_works_without_the_gvl() {
+        extern "C" {
+            fn rb_obj_freeze(object: Value) -> Value;
+        }
+
+        with_ruby(|| {
+            let name = CString::new("UnlockedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc);
+            let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            let val = without_gvl(thing, |data: &mut MyValue| {
+                data.val += 1;
+                data.val
+            });
+            assert_eq!(val, Ok(2));
+            assert_eq!(
+                without_gvl_unblock(thing, |data: &mut MyValue| data.val, || ()),
+                Ok(2)
+            );
+
+            // the crate refuses to be used from inside, and panics are passed on
+            let result = panic::catch_unwind(|| {
+                without_gvl(thing, |_: &mut MyValue| {
+                    with_ref(thing, |data: &MyValue| data.val)
+                })
+            });
+            assert!(result.is_err());
+            assert_eq!(with_ref(thing, |data: &MyValue| data.val), Ok(2));
+
+            // data holding Ruby values stays behind
+            let holder = alloc_marked(klass);
+            assert_eq!(
+                without_gvl(holder, |holder: &mut Holder| holder.values.len()),
+                Err(WrapError::Marked)
+            );
+
+            // reading needs only a shared borrow, so a frozen object will do
+            let thing = unsafe { rb_obj_freeze(thing) };
+            assert_eq!(
+                without_gvl(thing, |data: &mut MyValue| data.val),
+                Err(WrapError::Frozen)
+            );
+            assert_eq!(without_gvl_ref(thing, |data: &MyValue| data.val), Ok(2));
+            assert_eq!(with_ref(thing, |data: &MyValue| data.val), Ok(2));
+        });
+    }
+
+    struct Dropped(Sender<thread::ThreadId>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.send(thread::current().id()).unwrap();
+        }
+    }
+
+    static DROPPED_TYPE: DataType<Dropped> = DataType::new("Dropped\0").drop_in_background();
+
+    impl TypedData for Dropped {
+        fn data_type() -> &'static DataType<Dropped> {
+            &DROPPED_TYPE
+        }
+    }
+
+    // Made in a frame of their own so that nothing left on the stack keeps
+    // the objects alive; Ruby scans the stack conservatively, so some may
+    // survive anyway.
+    #[inline(never)]
+    fn make_garbage(klass: Value, sender: &Sender<thread::ThreadId>) {
+        for _ in 0..100 {
+            wrap_typed(klass, Some(Box::new(Dropped(sender.clone()))));
+        }
+    }
+
+    #[test]
+    fn it_drops_in_the_background() {
+        with_ruby(|| {
+            let name = CString::new("DroppedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            let (sender, dropped) = mpsc::channel();
+            make_garbage(klass, &sender);
+
+            // the free is deferred to the finalizers, which `rb_gc` runs
+            // once the collection is done
+            unsafe {
+                rb_gc();
+                rb_gc();
+            }
+            flush_background_drops();
+            let threads: Vec<_> = dropped.try_iter().collect();
+            assert!(!threads.is_empty(), "nothing was dropped");
+            for thread in threads {
+                assert_ne!(thread, thread::current().id());
+            }
+        });
+    }
 
-        // create our class
-        let name = CString::new("Thing").unwrap().into_raw();
-        let klass = unsafe { rb_define_class(name, rb_cObject) };
+    #[test]
+    fn it_raises_errors_as_exceptions() {
+        extern "C" {
+            static rb_eFrozenError: Value;
+            static rb_eTypeError: Value;
+        }
+
+        with_ruby(|| {
+            let error = protect(|| WrapError::Frozen.raise()).expect_err("raised");
+            assert_eq!(class_of(error).value, unsafe { rb_eFrozenError.value });
+
+            let error = protect(|| WrapError::NotData.raise()).expect_err("raised");
+            assert_eq!(class_of(error).value, unsafe { rb_eTypeError.value });
+        });
+    }
+
+    #[test]
+    fn it_shares_data_with_rust() {
+        with_ruby(|| {
+            let name = CString::new("SharedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+
+            // the object holds a reference of its own, and hands out more
+            let data = Rc::new(MyValue { val: 1 });
+            let thing = wrap_rc(klass, data.clone());
+            let shared = get_rc::<MyValue>(thing).unwrap();
+            assert!(Rc::ptr_eq(&data, &shared));
+            assert_eq!(Rc::strong_count(&data), 3);
+            assert_eq!(
+                get_arc::<MyValue>(thing).err(),
+                Some(WrapError::TypeMismatch)
+            );
+
+            // removing the data gives up the object's reference
+            drop(remove::<Rc<MyValue>>(thing).unwrap());
+            assert_eq!(Rc::strong_count(&data), 2);
+
+            let data = Arc::new(MyValue { val: 2 });
+            let thing = wrap_arc(klass, data.clone());
+            assert_eq!(get_arc::<MyValue>(thing).unwrap().val, 2);
+            assert_eq!(Arc::strong_count(&data), 2);
+        });
+    }
+
+    #[test]
+    fn it_wraps_shared_data_once() {
+        with_ruby(|| {
+            let name = CString::new("UniqueThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+
+            let data = Arc::new(MyValue { val: 1 });
+            let thing = wrap_arc_unique(klass, data.clone());
+            assert_eq!(wrap_arc_unique(klass, data.clone()).value, thing.value);
+            assert_eq!(Arc::strong_count(&data), 2);
+
+            // other data gets an object of its own
+            let other = wrap_arc_unique(klass, Arc::new(MyValue { val: 1 }));
+            assert!(other.value != thing.value);
+
+            // as does the same data once the first object lets go of it
+            remove::<Arc<MyValue>>(thing).unwrap();
+            assert!(wrap_arc_unique(klass, data.clone()).value != thing.value);
+
+            let data = Rc::new(MyValue { val: 2 });
+            let thing = wrap_rc_unique(klass, data.clone());
+            assert_eq!(wrap_rc_unique(klass, data).value, thing.value);
+        });
+    }
+
+    #[test]
+    fn it_replaces_takes_and_swaps() {
+        with_ruby(|| {
+            let name = CString::new("SwappedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc);
+            let a = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+            let b = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            // replace hands back the old value
+            let old = replace(a, Box::new(MyValue { val: 2 })).unwrap();
+            assert_eq!(old, Some(Box::new(MyValue { val: 1 })));
+
+            // swap exchanges the data, including an empty state
+            remove::<MyValue>(b).unwrap();
+            swap::<MyValue>(a, b).unwrap();
+            assert_eq!(remove::<MyValue>(a), Err(WrapError::Empty));
+            assert_eq!(with_ref(b, |data: &MyValue| data.val), Ok(2));
+            swap::<MyValue>(b, b).unwrap();
+            assert_eq!(with_ref(b, |data: &MyValue| data.val), Ok(2));
+            assert_eq!(swap::<Holder>(a, b), Err(WrapError::TypeMismatch));
+
+            // take leaves the default behind
+            let counter = wrap(klass, Some(Box::new(Counter(5))));
+            assert_eq!(take::<Counter>(counter), Ok(Box::new(Counter(5))));
+            assert_eq!(remove::<Counter>(counter), Ok(Box::new(Counter(0))));
+        });
+    }
+
+    #[test]
+    fn it_moves_data_by_value() {
+        with_ruby(|| {
+            let name = CString::new("UnboxedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+
+            let counter = wrap_value(klass, Counter(1));
+            assert_eq!(replace_value(counter, Counter(2)), Ok(Some(Counter(1))));
+            assert_eq!(take_value::<Counter>(counter), Ok(Counter(2)));
+            set_value(counter, Counter(3)).unwrap();
+            assert_eq!(remove_value::<Counter>(counter), Ok(Counter(3)));
+            assert_eq!(remove_value::<Counter>(counter), Err(WrapError::Empty));
+            assert_eq!(replace_value(counter, Counter(4)), Ok(None));
+
+            // boxes and values are interchangeable
+            assert_eq!(remove::<Counter>(counter), Ok(Box::new(Counter(4))));
 
-        // set up our alloc function and create the object
-        define_alloc_func(klass, alloc);
-        let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+            let thing = wrap_typed_value(klass, MyTypedValue { val: 5 });
+            assert_eq!(remove_typed(thing), Ok(Box::new(MyTypedValue { val: 5 })));
+        });
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    struct Counter(u32);
+
+    fn alloc_counter(klass: Value) -> Value {
+        wrap(klass, Some(Box::new(Counter(0))))
+    }
+
+    #[test]
+    fn it_copies_cloneable_data() {
+        extern "C" {
+            static rb_eTypeError: Value;
+        }
+
+        with_ruby(|| {
+            let name = CString::new("CopiedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc_counter);
+            define_clone::<Counter>(klass);
+            let counter = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+            set(counter, Box::new(Counter(5))).unwrap();
 
-        // the data matches what we put in
-        let data: Box<MyValue> = remove(thing).unwrap();
-        assert_eq!(*data, MyValue { val: 1 });
+            // the copy gets its own clone of the data
+            let copy = unsafe { rb_obj_dup(counter) };
+            with_mut(copy, |copy: &mut Counter| copy.0 += 1).unwrap();
+            assert_eq!(remove::<Counter>(copy), Ok(Box::new(Counter(6))));
+            assert_eq!(remove::<Counter>(counter), Ok(Box::new(Counter(5))));
 
-        // now it's None
-        assert_eq!(remove::<Option<Box<MyValue>>>(thing), None);
+            // copying an empty object fails
+            let error = protect(|| unsafe { rb_obj_dup(counter) }).expect_err("raised");
+            assert_eq!(class_of(error).value, unsafe { rb_eRuntimeError.value });
 
-        // set new data
-        let new_data = Box::new(MyValue { val: 2 });
-        set(thing, new_data);
+            // as does copying anything that can't be
+            let name = CString::new("UncopiedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc_counter);
+            forbid_clone(klass);
+            let counter = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+            let error = protect(|| unsafe { rb_obj_dup(counter) }).expect_err("raised");
+            assert_eq!(class_of(error).value, unsafe { rb_eTypeError.value });
+        });
+    }
+
+    impl fmt::Display for Counter {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "counted {}", self.0)
+        }
+    }
 
-        // looks right
-        let data: Box<MyValue> = remove(thing).unwrap();
-        assert_eq!(*data, MyValue { val: 2 });
+    // inspects the object it is wrapped by
+    struct Loop(Value);
 
-        // create our class
-        let name = CString::new("Thing2").unwrap().into_raw();
-        let klass = unsafe { rb_define_class(name, rb_cObject) };
+    impl fmt::Debug for Loop {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(&str_value(unsafe { rb_inspect(self.0) }))
+        }
+    }
+
+    extern "C" {
+        static rb_cBasicObject: Value;
+        fn rb_inspect(object: Value) -> Value;
+        fn rb_obj_as_string(object: Value) -> Value;
+        fn rb_obj_alloc(klass: Value) -> Value;
+    }
 
-        // set up our alloc function and create the object
-        define_alloc_func(klass, alloc_using_none);
-        let thing = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+    #[test]
+    fn it_inspects_wrapped_data() {
+        with_ruby(|| {
+            let name = CString::new("InspectedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc_counter);
+            define_inspect::<Counter>(klass);
+            define_to_s::<Counter>(klass);
+            let counter = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+            set(counter, Box::new(Counter(5))).unwrap();
+            let inspect = || str_value(unsafe { rb_inspect(counter) });
+            assert_eq!(inspect(), "#<InspectedThing Counter(5)>");
+            assert_eq!(str_value(unsafe { rb_obj_as_string(counter) }), "counted 5");
+
+            {
+                let _counter = unsafe { get_mut::<Counter>(counter) }.unwrap();
+                assert_eq!(inspect(), "#<InspectedThing (borrowed)>");
+            }
+            remove::<Counter>(counter).unwrap();
+            assert_eq!(inspect(), "#<InspectedThing (empty)>");
+
+            let name = CString::new("LoopedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_inspect::<Loop>(klass);
+            let looped = wrap::<Loop>(klass, None);
+            set(looped, Box::new(Loop(looped))).unwrap();
+            assert_eq!(
+                str_value(unsafe { rb_inspect(looped) }),
+                "#<LoopedThing #<LoopedThing ...>>"
+            );
+
+            // a raising `inspect` doesn't leave the object marked as being
+            // formatted
+            let basic = unsafe { rb_obj_alloc(rb_cBasicObject) };
+            set(looped, Box::new(Loop(basic))).unwrap();
+            protect(|| unsafe { rb_inspect(looped) }).expect_err("raised");
+            set(looped, Box::new(Loop(RB_NIL))).unwrap();
+            assert_eq!(
+                str_value(unsafe { rb_inspect(looped) }),
+                "#<LoopedThing nil>"
+            );
+        });
+    }
+
+    extern "C" {
+        fn rb_equal(object: Value, other: Value) -> Value;
+        fn rb_intern(name: *const c_char) -> usize;
+        fn rb_funcall(object: Value, method: usize, argc: c_int, ...) -> Value;
+    }
+
+    #[test]
+    fn it_compares_wrapped_data() {
+        extern "C" {
+            fn rb_eql(object: Value, other: Value) -> c_int;
+            fn rb_hash(object: Value) -> Value;
+            fn rb_hash_new() -> Value;
+            fn rb_hash_aset(hash: Value, key: Value, value: Value) -> Value;
+            fn rb_hash_aref(hash: Value, key: Value) -> Value;
+        }
+
+        with_ruby(|| {
+            let name = CString::new("ComparedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_eq::<Counter>(klass);
+            define_hash::<Counter>(klass);
+            let five = wrap(klass, Some(Box::new(Counter(5))));
+            let other_five = wrap(klass, Some(Box::new(Counter(5))));
+            let six = wrap(klass, Some(Box::new(Counter(6))));
+            let equal = |a, b| unsafe { rb_equal(a, b) }.value == True as usize;
+
+            assert!(equal(five, other_five));
+            assert!(!equal(five, six));
+            assert!(!equal(five, str_new("five")));
+            assert!(unsafe { rb_eql(five, other_five) } != 0);
+            assert_eq!(
+                unsafe { rb_hash(five) }.value,
+                unsafe { rb_hash(other_five) }.value
+            );
+
+            // equal data makes the same key
+            let hash = unsafe { rb_hash_new() };
+            let value = str_new("value");
+            unsafe { rb_hash_aset(hash, five, value) };
+            assert_eq!(unsafe { rb_hash_aref(hash, other_five) }.value, value.value);
+
+            // empty objects are only equal to themselves
+            remove::<Counter>(five).unwrap();
+            assert!(equal(five, five));
+            assert!(!equal(five, other_five));
+            assert!(!equal(other_five, five));
+            assert!(unsafe { rb_hash(five) }.value & 1 == 1);
+        });
+    }
 
-        // the data matches what we put in
-        assert!(remove::<Option<Box<MyValue>>>(thing).is_none());
+    #[test]
+    fn it_orders_wrapped_data() {
+        extern "C" {
+            fn rb_ary_new() -> Value;
+            fn rb_ary_push(array: Value, item: Value) -> Value;
+        }
+
+        with_ruby(|| {
+            let name = CString::new("OrderedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_cmp::<Counter>(klass);
+            let five = wrap(klass, Some(Box::new(Counter(5))));
+            let six = wrap(klass, Some(Box::new(Counter(6))));
+            let fixnum = |n: isize| ((n << 1) | 1) as usize;
+            let send = |object: Value, method: &str, arg: Value| unsafe {
+                let method = CString::new(method).unwrap();
+                rb_funcall(object, rb_intern(method.as_ptr()), 1, arg)
+            };
+
+            assert_eq!(send(five, "<=>", six).value, fixnum(-1));
+            assert_eq!(send(six, "<=>", five).value, fixnum(1));
+            assert_eq!(send(five, "<=>", five).value, fixnum(0));
+            assert_eq!(send(five, "<=>", str_new("six")).value, RB_NIL.value);
+
+            // from Comparable
+            assert_eq!(send(five, "<", six).value, True as usize);
+            let array = unsafe { rb_ary_new() };
+            unsafe {
+                rb_ary_push(array, six);
+                rb_ary_push(array, five);
+            }
+            let max =
+                unsafe { rb_funcall(array, rb_intern(b"max\0".as_ptr() as *const c_char), 0) };
+            assert_eq!(max.value, six.value);
+        });
+    }
+
+    #[derive(Debug)]
+    struct Index(Vec<u32>);
+
+    impl<'a> IntoIterator for &'a Index {
+        type Item = &'a u32;
+        type IntoIter = ::std::slice::Iter<'a, u32>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.iter()
+        }
+    }
+
+    #[test]
+    fn it_enumerates_wrapped_collections() {
+        extern "C" {
+            static rb_cEnumerator: Value;
+            fn rb_block_call(
+                object: Value,
+                method: usize,
+                argc: c_int,
+                argv: *const Value,
+                block: extern "C" fn(Value, Value, c_int, *const Value, Value) -> Value,
+                data: Value,
+            ) -> Value;
+            fn rb_num2long(number: Value) -> c_long;
+        }
+
+        // tries to empty the object being iterated over
+        extern "C" fn remove_during_each(
+            _item: Value,
+            index: Value,
+            _argc: c_int,
+            _argv: *const Value,
+            _block: Value,
+        ) -> Value {
+            remove::<Index>(index).unwrap_err().raise()
+        }
+
+        with_ruby(|| {
+            let name = CString::new("EnumeratedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_each::<Index>(klass);
+            let index = wrap(klass, Some(Box::new(Index(vec![3, 1, 2]))));
+            let call = |method: &[u8]| unsafe {
+                rb_funcall(index, rb_intern(method.as_ptr() as *const c_char), 0)
+            };
+
+            // from Enumerable
+            let sum = call(b"sum\0");
+            assert_eq!(unsafe { rb_num2long(sum) }, 6);
+            let sorted = call(b"sort\0");
+            assert_eq!(str_value(unsafe { rb_inspect(sorted) }), "[1, 2, 3]");
+            let enumerator = call(b"each\0");
+            assert_eq!(class_of(enumerator).value, unsafe { rb_cEnumerator.value });
+
+            // the data can't be taken away mid-iteration, and the borrow is
+            // released when the block raises
+            let each = unsafe { rb_intern(b"each\0".as_ptr() as *const c_char) };
+            let error = protect(|| unsafe {
+                rb_block_call(index, each, 0, ptr::null(), remove_during_each, index)
+            })
+            .expect_err("raised");
+            assert_eq!(class_of(error).value, unsafe { rb_eRuntimeError.value });
+
+            // as it is by `break` (which `first` uses)
+            call(b"first\0");
+            assert_eq!(
+                remove::<Index>(index).map(|index| index.0),
+                Ok(vec![3, 1, 2])
+            );
+        });
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Vector(Vec<f64>);
+
+    static VECTOR_TYPE: DataType<Vector> = DataType::new("Vector\0");
+
+    impl TypedData for Vector {
+        fn data_type() -> &'static DataType<Vector> {
+            &VECTOR_TYPE
+        }
+    }
+
+    impl ops::Add for Vector {
+        type Output = Vector;
+
+        fn add(self, other: Vector) -> Vector {
+            Vector(self.0.iter().zip(&other.0).map(|(a, b)| a + b).collect())
+        }
+    }
+
+    impl ops::Mul<f64> for Vector {
+        type Output = Vector;
+
+        fn mul(self, factor: f64) -> Vector {
+            Vector(self.0.iter().map(|a| a * factor).collect())
+        }
+    }
+
+    impl ops::Neg for Vector {
+        type Output = Vector;
+
+        fn neg(self) -> Vector {
+            self * -1.0
+        }
+    }
+
+    impl TryIndex<usize> for Vector {
+        type Output = f64;
+
+        fn try_index(&self, index: usize) -> Option<&f64> {
+            self.0.try_index(index)
+        }
+    }
+
+    impl TryIndexMut<usize> for Vector {
+        fn try_index_mut(&mut self, index: usize) -> Option<&mut f64> {
+            self.0.try_index_mut(index)
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Money(i64);
+
+    static MONEY_TYPE: DataType<Money> = DataType::new("Money\0");
+
+    impl TypedData for Money {
+        fn data_type() -> &'static DataType<Money> {
+            &MONEY_TYPE
+        }
+    }
+
+    impl From<i64> for Money {
+        fn from(cents: i64) -> Money {
+            Money(cents)
+        }
+    }
+
+    impl ops::Add for Money {
+        type Output = Money;
+
+        fn add(self, other: Money) -> Money {
+            Money(self.0 + other.0)
+        }
+    }
+
+    impl ops::Sub for Money {
+        type Output = Money;
+
+        fn sub(self, other: Money) -> Money {
+            Money(self.0 - other.0)
+        }
+    }
+
+    #[test]
+    fn it_maps_operators() {
+        extern "C" {
+            static rb_eIndexError: Value;
+            static rb_eTypeError: Value;
+            fn rb_funcallv(object: Value, method: usize, argc: c_int, argv: *const Value) -> Value;
+        }
+
+        with_ruby(|| {
+            let send = |object: Value, method: &str, args: &[Value]| unsafe {
+                let method = CString::new(method).unwrap();
+                let method = rb_intern(method.as_ptr());
+                rb_funcallv(object, method, args.len() as c_int, args.as_ptr())
+            };
+            let vector = |value: Value| with_ref(value, |vector: &Vector| vector.0.clone());
+
+            let name = CString::new("OperatedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_add::<Vector, Vector>(klass);
+            define_mul::<Vector, f64>(klass);
+            define_neg::<Vector>(klass);
+            define_index::<Vector, usize>(klass);
+            define_index_mut::<Vector, usize>(klass);
+            let a = wrap_typed(klass, Some(Box::new(Vector(vec![1.0, 2.0]))));
+            let b = wrap_typed(klass, Some(Box::new(Vector(vec![3.0, 4.0]))));
+            let two = 2u8.into_value();
+
+            let sum = send(a, "+", &[b]);
+            assert_eq!(class_of(sum).value, klass.value);
+            assert_eq!(vector(sum), Ok(vec![4.0, 6.0]));
+            assert_eq!(vector(send(a, "*", &[two])), Ok(vec![2.0, 4.0]));
+            assert_eq!(vector(send(a, "-@", &[])), Ok(vec![-1.0, -2.0]));
+
+            assert_eq!(
+                f64::from_value(send(a, "[]", &[1u8.into_value()])),
+                Some(2.0)
+            );
+            send(a, "[]=", &[0u8.into_value(), 5.5.into_value()]);
+            assert_eq!(vector(a), Ok(vec![5.5, 2.0]));
+
+            // out of range is `nil` to read and an `IndexError` to write
+            let nil = send(a, "[]", &[2u8.into_value()]);
+            assert_eq!(nil.value, RB_NIL.value);
+            let error = protect(|| send(a, "[]=", &[2u8.into_value(), 1.0.into_value()]))
+                .expect_err("raised");
+            assert_eq!(class_of(error).value, unsafe { rb_eIndexError.value });
+            assert_eq!(vector(a), Ok(vec![5.5, 2.0]));
+
+            // the receiver is left alone
+            assert_eq!(vector(b), Ok(vec![3.0, 4.0]));
+
+            let error = protect(|| send(a, "+", &[str_new("b")])).expect_err("raised");
+            assert_eq!(class_of(error).value, unsafe { rb_eTypeError.value });
+
+            // numbers on the left are converted, keeping the operands in order
+            let name = CString::new("CoercedThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_add::<Money, Money>(klass);
+            define_sub::<Money, Money>(klass);
+            define_coerce::<Money, i64>(klass);
+            let three = wrap_typed(klass, Some(Box::new(Money(3))));
+            let difference = send(10i64.into_value(), "-", &[three]);
+            assert_eq!(class_of(difference).value, klass.value);
+            assert_eq!(Money::from_value(difference), Some(Money(7)));
+            let sum = send(10i64.into_value(), "+", &[three]);
+            assert_eq!(Money::from_value(sum), Some(Money(13)));
+
+            let error = protect(|| send(1.5.into_value(), "+", &[three])).expect_err("raised");
+            assert_eq!(class_of(error).value, unsafe { rb_eTypeError.value });
+        });
+    }
+
+    struct Measured(Vec<u32>);
+
+    impl MemSize for Measured {
+        fn memsize(&self) -> usize {
+            mem::size_of::<Measured>() + self.0.heap_size()
+        }
+    }
+
+    static MEASURED_TYPE: DataType<Measured> = DataType::new("Measured\0").memsize();
+
+    impl TypedData for Measured {
+        fn data_type() -> &'static DataType<Measured> {
+            &MEASURED_TYPE
+        }
+    }
+
+    #[test]
+    fn it_measures_heap_memory() {
+        extern "C" {
+            fn rb_obj_memsize_of(object: Value) -> usize;
+        }
+
+        let numbers: Vec<u32> = Vec::with_capacity(8);
+        assert_eq!(numbers.memsize(), mem::size_of::<Vec<u32>>() + 32);
+
+        let name = String::from("four");
+        assert_eq!(name.heap_size(), name.capacity());
+
+        let names = vec![name.clone(), name.clone()];
+        let expected = mem::size_of::<Vec<String>>() + 2 * name.memsize();
+        assert_eq!(names.memsize(), expected);
+
+        assert_eq!(Some(Box::new(7u64)).heap_size(), 8);
+        assert_eq!(None::<String>.memsize(), mem::size_of::<Option<String>>());
+
+        // which is what `ObjectSpace.memsize_of` sees
+        with_ruby(|| {
+            let name = CString::new("MeasuredThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            let small = wrap_typed(klass, Some(Box::new(Measured(Vec::new()))));
+            let large = Measured(Vec::with_capacity(1000));
+            let large = wrap_typed(klass, Some(Box::new(large)));
+            let (small, large) = unsafe { (rb_obj_memsize_of(small), rb_obj_memsize_of(large)) };
+            assert!(small >= mem::size_of::<Slot<Measured>>());
+            assert_eq!(large - small, 1000 * mem::size_of::<u32>());
+        });
+    }
+
+    #[cfg(feature = "compact")]
+    struct MovableHolder {
+        values: Vec<Value>,
+    }
+
+    #[cfg(feature = "compact")]
+    impl Mark for MovableHolder {
+        fn mark(&self) {
+            self.values.mark();
+        }
+    }
+
+    #[cfg(feature = "compact")]
+    impl Compact for MovableHolder {
+        fn mark_movable(&self) {
+            self.values.mark_movable();
+        }
+
+        fn compact(&mut self) {
+            self.values.compact();
+        }
+    }
+
+    #[cfg(feature = "compact")]
+    static MOVABLE_HOLDER_TYPE: DataType<MovableHolder> =
+        DataType::new("MovableHolder\0").compact();
+
+    #[cfg(feature = "compact")]
+    impl TypedData for MovableHolder {
+        fn data_type() -> &'static DataType<MovableHolder> {
+            &MOVABLE_HOLDER_TYPE
+        }
+    }
+
+    #[cfg(feature = "compact")]
+    #[test]
+    fn it_survives_compaction() {
+        extern "C" {
+            fn rb_eval_string(source: *const c_char) -> Value;
+            fn rb_gv_set(name: *const c_char, value: Value) -> Value;
+            fn rb_gv_get(name: *const c_char) -> Value;
+        }
+
+        with_ruby(|| {
+            let name = CString::new("MovableThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            let values = vec![str_new("foo"), str_new("bar")];
+            let thing = wrap_typed(klass, Some(Box::new(MovableHolder { values })));
+
+            let source = CString::new("GC.compact").unwrap();
+            unsafe { rb_eval_string(source.as_ptr()) };
+
+            // the strings are still strings, wherever they are now
+            let data: Box<MovableHolder> = remove_typed(thing).unwrap();
+            for value in data.values {
+                assert_eq!(builtin_type(value), T_STRING);
+            }
+
+            // unique objects stay put, so they are still found
+            let data = Arc::new(MyValue { val: 1 });
+            let global = CString::new("$unique").unwrap();
+            unsafe { rb_gv_set(global.as_ptr(), wrap_arc_unique(klass, data.clone())) };
+            unsafe { rb_eval_string(source.as_ptr()) };
+            let unique = unsafe { rb_gv_get(global.as_ptr()) };
+            assert_eq!(wrap_arc_unique(klass, data).value, unique.value);
+        });
+    }
+
+    #[cfg(feature = "derive")]
+    #[derive(Clone, Debug, Default, PartialEq, RubyWrap)]
+    #[ruby(class = "Geometry::Point", free_immediately, clone, inspect, eq)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn it_derives_a_class() {
+        with_ruby(|| {
+            let klass = Point::define_class();
+            let point = unsafe { rb_class_new_instance(0, &RB_NIL, klass) };
+
+            // new instances hold the default value
+            assert_eq!(remove_typed(point), Ok(Box::new(Point { x: 0, y: 0 })));
+            set_typed(point, Box::new(Point { x: 1, y: 2 })).unwrap();
+            assert_eq!(with_ref(point, |point: &Point| point.y), Ok(2));
+
+            // copies get a clone of the data
+            let copy = unsafe { rb_obj_dup(point) };
+            assert_eq!(with_ref(copy, |point: &Point| point.y), Ok(2));
+
+            assert_eq!(
+                str_value(unsafe { rb_inspect(point) }),
+                "#<Geometry::Point Point { x: 1, y: 2 }>"
+            );
+            assert!(unsafe { rb_equal(point, copy) }.value == True as usize);
+
+            // defining it again finds the existing module and class
+            assert_eq!(Point::define_class().value, klass.value);
+        });
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        retries: u32,
+    }
+
+    #[cfg(feature = "serde")]
+    fn alloc_config(klass: Value) -> Value {
+        wrap(klass, Some(Box::new(Config::default())))
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_marshals_serializable_data() {
+        extern "C" {
+            fn rb_marshal_dump(object: Value, port: Value) -> Value;
+            fn rb_marshal_load(dump: Value) -> Value;
+        }
+
+        with_ruby(|| {
+            let name = CString::new("MarshaledThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc_config);
+            define_marshal::<Config>(klass);
+            let config = Config {
+                name: "primary".to_string(),
+                retries: 3,
+            };
+            let thing = wrap(klass, Some(Box::new(config)));
+
+            let dump = unsafe { rb_marshal_dump(thing, RB_NIL) };
+            let copy = unsafe { rb_marshal_load(dump) };
+            assert!(copy.value != thing.value);
+            let config = remove::<Config>(copy).unwrap();
+            assert_eq!(config.name, "primary");
+            assert_eq!(config.retries, 3);
+
+            // an empty object has nothing to dump
+            remove::<Config>(thing).unwrap();
+            let error = protect(|| unsafe { rb_marshal_dump(thing, RB_NIL) });
+            assert!(error.is_err());
+        });
+    }
+
+    fn alloc_panicking(_klass: Value) -> Value {
+        panic!("out of widgets");
+    }
+
+    #[test]
+    fn it_raises_panics_in_alloc() {
+        with_ruby(|| {
+            let name = CString::new("PanickyThing").unwrap().into_raw();
+            let klass = unsafe { rb_define_class(name, rb_cObject) };
+            define_alloc_func(klass, alloc_panicking);
+
+            let error = protect(|| unsafe { rb_class_new_instance(0, &RB_NIL, klass) })
+                .expect_err("alloc should raise");
+            assert_eq!(class_of(error).value, unsafe { rb_eRuntimeError.value });
+
+            // subclasses use (and look up) the same alloc function
+            let name = CString::new("PanickySubThing").unwrap().into_raw();
+            let subclass = unsafe { rb_define_class(name, klass) };
+            let error = protect(|| unsafe { rb_class_new_instance(0, &RB_NIL, subclass) });
+            assert!(error.is_err());
+        });
     }
 }
@@ -0,0 +1,79 @@
+//! `Marshal` support for wrapped types implementing serde's traits (behind
+//! the `serde` feature).
+//!
+//! The data is dumped with bincode, after a byte giving the version of that
+//! format, so that dumps made by an incompatible future version of this
+//! crate are refused rather than misread.
+
+use bincode;
+use ruby_sys::types::{CallbackPtr, Value};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::os::raw::{c_char, c_long};
+use std::slice;
+
+use super::convert::{rb_str_bytesize, rb_string_value_ptr};
+use super::error::rb_eTypeError;
+use super::method::{call, define_method, Failure};
+use super::{builtin_type, is_special_const, set_value, with_ref, T_STRING};
+
+extern "C" {
+    static rb_eArgError: Value;
+    fn rb_str_new(ptr: *const c_char, len: c_long) -> Value;
+    fn rb_num2long(number: Value) -> c_long;
+}
+
+const FORMAT_VERSION: u8 = 1;
+
+/// Defines `marshal_dump` and `marshal_load` for a Ruby class wrapping a
+/// `T`, so its instances can be dumped with `Marshal.dump` and loaded back
+/// with `Marshal.load`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`, with an alloc function
+pub fn define_marshal<T: Serialize + DeserializeOwned + 'static>(klass: Value) {
+    let dump = marshal_dump::<T> as extern "C" fn(Value) -> Value;
+    let load = marshal_load::<T> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"marshal_dump\0", dump as CallbackPtr, 0);
+    define_method(klass, b"marshal_load\0", load as CallbackPtr, 1);
+}
+
+extern "C" fn marshal_dump<T: Serialize + 'static>(object: Value) -> Value {
+    call(|| dump::<T>(object))
+}
+
+extern "C" fn marshal_load<T: DeserializeOwned + 'static>(object: Value, dump: Value) -> Value {
+    call(|| load::<T>(object, dump))
+}
+
+fn dump<T: Serialize + 'static>(object: Value) -> Result<Value, Failure> {
+    let mut bytes = vec![FORMAT_VERSION];
+    with_ref(object, |data: &T| bincode::serialize_into(&mut bytes, data))?
+        .map_err(|error| Failure::new(unsafe { rb_eTypeError }, error.to_string()))?;
+    Ok(unsafe { rb_str_new(bytes.as_ptr() as *const c_char, bytes.len() as c_long) })
+}
+
+fn load<T: DeserializeOwned + 'static>(object: Value, mut dump: Value) -> Result<Value, Failure> {
+    if is_special_const(dump) || builtin_type(dump) != T_STRING {
+        let message = "marshal data must be a String".to_string();
+        return Err(Failure::new(unsafe { rb_eTypeError }, message));
+    }
+    let bytes = unsafe {
+        let ptr = rb_string_value_ptr(&mut dump) as *const u8;
+        slice::from_raw_parts(ptr, rb_num2long(rb_str_bytesize(dump)) as usize)
+    };
+    match bytes.split_first() {
+        Some((&FORMAT_VERSION, bytes)) => {
+            let data: T = bincode::deserialize(bytes)
+                .map_err(|error| Failure::new(unsafe { rb_eArgError }, error.to_string()))?;
+            set_value(object, data)?;
+            Ok(object)
+        }
+        _ => {
+            let message = "unsupported marshal data format".to_string();
+            Err(Failure::new(unsafe { rb_eArgError }, message))
+        }
+    }
+}
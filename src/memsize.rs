@@ -0,0 +1,130 @@
+//! Reporting the size of wrapped data to Ruby.
+//!
+//! Typed data always reports the inline size of the wrapped `T` through its
+//! `dsize` callback, which is what `ObjectSpace.memsize_of` uses. Types that
+//! own memory on the heap should implement `MemSize` and opt in with
+//! `DataType::memsize` so that memory is counted too.
+
+use ruby_sys::types::{c_void, Value};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::mem;
+
+use super::unwind::abort_on_panic;
+use super::{gvl, Slot};
+
+/// Implemented by wrapped data that wants to report its own memory usage.
+///
+/// The default counts only `size_of::<Self>()`. Override it for types that
+/// own heap memory:
+///
+/// ```rust,ignore
+/// struct Index {
+///     name: String,
+///     records: Vec<Record>,
+/// }
+///
+/// impl MemSize for Index {
+///     fn memsize(&self) -> usize {
+///         mem::size_of::<Index>() + self.name.heap_size() + self.records.heap_size()
+///     }
+/// }
+/// ```
+pub trait MemSize {
+    /// Returns the number of bytes `self` occupies, including any heap
+    /// memory it owns.
+    fn memsize(&self) -> usize {
+        mem::size_of_val(self)
+    }
+
+    /// Returns the number of bytes `self` owns on the heap, i.e. its
+    /// `memsize` less its inline size.
+    fn heap_size(&self) -> usize {
+        self.memsize() - mem::size_of_val(self)
+    }
+}
+
+macro_rules! impl_inline_memsize {
+    ($($ty:ty),*) => {
+        $(impl MemSize for $ty {})*
+    };
+}
+
+impl_inline_memsize! {
+    (), bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, Value
+}
+
+impl MemSize for String {
+    fn memsize(&self) -> usize {
+        mem::size_of::<String>() + self.capacity()
+    }
+}
+
+impl<T: MemSize + ?Sized> MemSize for Box<T> {
+    fn memsize(&self) -> usize {
+        mem::size_of::<Box<T>>() + (**self).memsize()
+    }
+}
+
+impl<T: MemSize> MemSize for Option<T> {
+    fn memsize(&self) -> usize {
+        mem::size_of::<Option<T>>() + self.as_ref().map_or(0, MemSize::heap_size)
+    }
+}
+
+impl<T: MemSize> MemSize for [T] {
+    fn memsize(&self) -> usize {
+        self.iter().map(MemSize::memsize).sum()
+    }
+}
+
+impl<T: MemSize> MemSize for Vec<T> {
+    fn memsize(&self) -> usize {
+        let spare = self.capacity() - self.len();
+        mem::size_of::<Vec<T>>() + spare * mem::size_of::<T>() + self[..].memsize()
+    }
+}
+
+impl<T: MemSize> MemSize for VecDeque<T> {
+    fn memsize(&self) -> usize {
+        let spare = self.capacity() - self.len();
+        let items: usize = self.iter().map(MemSize::memsize).sum();
+        mem::size_of::<VecDeque<T>>() + spare * mem::size_of::<T>() + items
+    }
+}
+
+// Hash tables keep some bookkeeping per bucket on top of the entries
+// themselves; this only counts the entries, so treat it as a lower bound.
+impl<K: MemSize, V: MemSize, S: BuildHasher> MemSize for HashMap<K, V, S> {
+    fn memsize(&self) -> usize {
+        let spare = self.capacity() - self.len();
+        let entries: usize = self.iter().map(|(k, v)| k.memsize() + v.memsize()).sum();
+        mem::size_of::<HashMap<K, V, S>>() + spare * mem::size_of::<(K, V)>() + entries
+    }
+}
+
+impl<K: MemSize, V: MemSize> MemSize for BTreeMap<K, V> {
+    fn memsize(&self) -> usize {
+        let entries: usize = self.iter().map(|(k, v)| k.memsize() + v.memsize()).sum();
+        mem::size_of::<BTreeMap<K, V>>() + entries
+    }
+}
+
+// Every typed object reports at least the size of its slot, which holds the
+// data inline.
+pub(crate) extern "C" fn size<T>(_: *const c_void) -> usize {
+    mem::size_of::<Slot<T>>()
+}
+
+pub(crate) extern "C" fn memsize<T: MemSize>(data: *const c_void) -> usize {
+    let slot = unsafe { &*(data as *const Slot<T>) };
+    if slot.borrow.get() == gvl::UNLOCKED {
+        // another thread is busy with the data, so it can't be looked into
+        return size::<T>(data);
+    }
+    let size = abort_on_panic("memsize", || {
+        slot.data.as_ref().map_or(0, |data| data.heap_size())
+    });
+    mem::size_of::<Slot<T>>() + size
+}
@@ -0,0 +1,70 @@
+//! Plumbing for the Ruby methods this crate defines on wrapped classes
+//! (`initialize_copy`, `inspect`, and so on).
+
+use ruby_sys::types::{CallbackPtr, Value};
+use ruby_sys::value::RubySpecialConsts::{False, Nil, True};
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+
+use super::error::raise;
+use super::{rescue_panic, WrapError};
+
+extern "C" {
+    fn rb_define_method(klass: Value, name: *const c_char, func: CallbackPtr, argc: c_int);
+    fn rb_obj_classname(object: Value) -> *const c_char;
+}
+
+// An exception for a method to raise.
+pub(crate) struct Failure {
+    class: Value,
+    message: String,
+}
+
+impl Failure {
+    pub(crate) fn new(class: Value, message: String) -> Failure {
+        Failure { class, message }
+    }
+}
+
+impl From<WrapError> for Failure {
+    fn from(error: WrapError) -> Failure {
+        Failure::new(error.exception_class(), error.to_string())
+    }
+}
+
+// Defines an instance method; `name` must end with a `\0`.
+pub(crate) fn define_method(klass: Value, name: &'static [u8], func: CallbackPtr, argc: c_int) {
+    debug_assert_eq!(name.last(), Some(&0));
+    unsafe { rb_define_method(klass, name.as_ptr() as *const c_char, func, argc) };
+}
+
+// Runs the body of a method, raising its failure (or panic) as a Ruby
+// exception once there's nothing left in Rust to leak.
+pub(crate) fn call<F: FnOnce() -> Result<Value, Failure>>(f: F) -> Value {
+    let mut failure = None;
+    let value = rescue_panic(|| {
+        f().unwrap_or_else(|error| {
+            failure = Some(error);
+            Value {
+                value: Nil as usize,
+            }
+        })
+    });
+    if let Some(failure) = failure {
+        raise(failure.class, failure.message);
+    }
+    value
+}
+
+pub(crate) fn boolean(b: bool) -> Value {
+    let value = if b { True } else { False };
+    Value {
+        value: value as usize,
+    }
+}
+
+pub(crate) fn class_name(object: Value) -> String {
+    let name = unsafe { CStr::from_ptr(rb_obj_classname(object)) };
+    name.to_string_lossy().into_owned()
+}
@@ -0,0 +1,329 @@
+//! Ruby operators from the `std::ops` traits.
+//!
+//! The operators work on clones of the receiver's data, as the traits take
+//! it by value, and wrap the result in a new instance of the receiver's
+//! class. Operands are converted with `FromValue`, raising a `TypeError` if
+//! they can't be.
+
+use ruby_sys::types::{CallbackPtr, Value};
+
+use std::any;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use super::convert::{rb_assoc_new, FromValue, IntoValue};
+use super::error::rb_eTypeError;
+use super::method::{call, class_name, define_method, Failure};
+use super::{with_mut, with_ref, wrap_typed_value, TypedData};
+
+extern "C" {
+    static rb_eIndexError: Value;
+    fn rb_obj_class(object: Value) -> Value;
+}
+
+/// Defines `+` for a Ruby class wrapping a `T`, adding an `R` with `Add`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_add<T, R>(klass: Value)
+where
+    T: Add<R, Output = T> + TypedData + Clone,
+    R: FromValue,
+{
+    let func = add::<T, R> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"+\0", func as CallbackPtr, 1);
+}
+
+/// Defines `-` for a Ruby class wrapping a `T`, subtracting an `R` with
+/// `Sub`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_sub<T, R>(klass: Value)
+where
+    T: Sub<R, Output = T> + TypedData + Clone,
+    R: FromValue,
+{
+    let func = sub::<T, R> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"-\0", func as CallbackPtr, 1);
+}
+
+/// Defines `*` for a Ruby class wrapping a `T`, multiplying by an `R` with
+/// `Mul`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_mul<T, R>(klass: Value)
+where
+    T: Mul<R, Output = T> + TypedData + Clone,
+    R: FromValue,
+{
+    let func = mul::<T, R> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"*\0", func as CallbackPtr, 1);
+}
+
+/// Defines `/` for a Ruby class wrapping a `T`, dividing by an `R` with
+/// `Div`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_div<T, R>(klass: Value)
+where
+    T: Div<R, Output = T> + TypedData + Clone,
+    R: FromValue,
+{
+    let func = div::<T, R> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"/\0", func as CallbackPtr, 1);
+}
+
+/// Defines unary minus (`-@`) for a Ruby class wrapping a `T`, with `Neg`.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_neg<T>(klass: Value)
+where
+    T: Neg<Output = T> + TypedData + Clone,
+{
+    let func = neg::<T> as extern "C" fn(Value) -> Value;
+    define_method(klass, b"-@\0", func as CallbackPtr, 0);
+}
+
+/// Like `Index`, but returning `None` for an index that's out of range,
+/// which `define_index` turns into `nil` rather than a panic. Implemented
+/// for `Vec` and `VecDeque` (by `usize`) and `HashMap` and `BTreeMap` (by
+/// key).
+pub trait TryIndex<I> {
+    /// The type of the elements.
+    type Output;
+
+    /// Returns the element at `index`, if there is one.
+    fn try_index(&self, index: I) -> Option<&Self::Output>;
+}
+
+/// Like `IndexMut`, but returning `None` for an index that's out of range,
+/// which `define_index_mut` raises as an `IndexError`.
+pub trait TryIndexMut<I>: TryIndex<I> {
+    /// Returns the element at `index` for changing, if there is one.
+    fn try_index_mut(&mut self, index: I) -> Option<&mut Self::Output>;
+}
+
+impl<T> TryIndex<usize> for Vec<T> {
+    type Output = T;
+
+    fn try_index(&self, index: usize) -> Option<&T> {
+        self.as_slice().get(index)
+    }
+}
+
+impl<T> TryIndexMut<usize> for Vec<T> {
+    fn try_index_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.as_mut_slice().get_mut(index)
+    }
+}
+
+impl<T> TryIndex<usize> for VecDeque<T> {
+    type Output = T;
+
+    fn try_index(&self, index: usize) -> Option<&T> {
+        self.get(index)
+    }
+}
+
+impl<T> TryIndexMut<usize> for VecDeque<T> {
+    fn try_index_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.get_mut(index)
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> TryIndex<K> for HashMap<K, V, S> {
+    type Output = V;
+
+    fn try_index(&self, key: K) -> Option<&V> {
+        self.get(&key)
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> TryIndexMut<K> for HashMap<K, V, S> {
+    fn try_index_mut(&mut self, key: K) -> Option<&mut V> {
+        self.get_mut(&key)
+    }
+}
+
+impl<K: Ord, V> TryIndex<K> for BTreeMap<K, V> {
+    type Output = V;
+
+    fn try_index(&self, key: K) -> Option<&V> {
+        self.get(&key)
+    }
+}
+
+impl<K: Ord, V> TryIndexMut<K> for BTreeMap<K, V> {
+    fn try_index_mut(&mut self, key: K) -> Option<&mut V> {
+        self.get_mut(&key)
+    }
+}
+
+/// Defines `[]` for a Ruby class wrapping a `T`, looking up an `I` with
+/// `TryIndex` and converting a clone of the element with `IntoValue`, or
+/// returning `nil` if there is none, as Ruby's own collections do.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_index<T, I>(klass: Value)
+where
+    T: TryIndex<I> + 'static,
+    T::Output: IntoValue + Clone,
+    I: FromValue,
+{
+    let func = index::<T, I> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"[]\0", func as CallbackPtr, 1);
+}
+
+/// Defines `[]=` for a Ruby class wrapping a `T`, assigning an element
+/// converted with `FromValue` through `TryIndexMut`. Assigning to an index
+/// that's out of range raises an `IndexError`; nothing is inserted.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_index_mut<T, I>(klass: Value)
+where
+    T: TryIndexMut<I> + 'static,
+    T::Output: FromValue,
+    I: FromValue,
+{
+    let func = index_mut::<T, I> as extern "C" fn(Value, Value, Value) -> Value;
+    define_method(klass, b"[]=\0", func as CallbackPtr, 2);
+}
+
+/// Defines `coerce` for a Ruby class wrapping a `T`, so numeric operators
+/// with an instance on the right (`10 - money`) convert the number (an `R`)
+/// into a new instance with `From` and carry on with that on the left
+/// (`money(10) - money`).
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class whose instances wrap a `T`
+pub fn define_coerce<T, R>(klass: Value)
+where
+    T: From<R> + TypedData,
+    R: FromValue,
+{
+    let func = coerce::<T, R> as extern "C" fn(Value, Value) -> Value;
+    define_method(klass, b"coerce\0", func as CallbackPtr, 1);
+}
+
+extern "C" fn add<T: Add<R, Output = T> + TypedData + Clone, R: FromValue>(
+    object: Value,
+    other: Value,
+) -> Value {
+    call(|| binary(object, other, |data: T, other: R| data + other))
+}
+
+extern "C" fn sub<T: Sub<R, Output = T> + TypedData + Clone, R: FromValue>(
+    object: Value,
+    other: Value,
+) -> Value {
+    call(|| binary(object, other, |data: T, other: R| data - other))
+}
+
+extern "C" fn mul<T: Mul<R, Output = T> + TypedData + Clone, R: FromValue>(
+    object: Value,
+    other: Value,
+) -> Value {
+    call(|| binary(object, other, |data: T, other: R| data * other))
+}
+
+extern "C" fn div<T: Div<R, Output = T> + TypedData + Clone, R: FromValue>(
+    object: Value,
+    other: Value,
+) -> Value {
+    call(|| binary(object, other, |data: T, other: R| data / other))
+}
+
+extern "C" fn neg<T: Neg<Output = T> + TypedData + Clone>(object: Value) -> Value {
+    call(|| {
+        let data = with_ref(object, T::clone)?;
+        Ok(new_instance(object, -data))
+    })
+}
+
+extern "C" fn index<T, I>(object: Value, index: Value) -> Value
+where
+    T: TryIndex<I> + 'static,
+    T::Output: IntoValue + Clone,
+    I: FromValue,
+{
+    call(|| {
+        let index = operand::<I>(index)?;
+        let element = with_ref(object, |data: &T| data.try_index(index).cloned())?;
+        Ok(element.into_value())
+    })
+}
+
+extern "C" fn index_mut<T, I>(object: Value, index: Value, value: Value) -> Value
+where
+    T: TryIndexMut<I> + 'static,
+    T::Output: FromValue,
+    I: FromValue,
+{
+    call(|| {
+        let index = operand::<I>(index)?;
+        let element = operand::<T::Output>(value)?;
+        let assigned = with_mut(object, |data: &mut T| match data.try_index_mut(index) {
+            Some(slot) => {
+                *slot = element;
+                true
+            }
+            None => false,
+        })?;
+        if assigned {
+            Ok(value)
+        } else {
+            Err(Failure::new(
+                unsafe { rb_eIndexError },
+                "index out of range".to_string(),
+            ))
+        }
+    })
+}
+
+extern "C" fn coerce<T: From<R> + TypedData, R: FromValue>(object: Value, other: Value) -> Value {
+    call(|| {
+        let other = new_instance(object, T::from(operand::<R>(other)?));
+        Ok(unsafe { rb_assoc_new(other, object) })
+    })
+}
+
+fn binary<T, R, F>(object: Value, other: Value, f: F) -> Result<Value, Failure>
+where
+    T: TypedData + Clone,
+    R: FromValue,
+    F: FnOnce(T, R) -> T,
+{
+    let other = operand::<R>(other)?;
+    let data = with_ref(object, T::clone)?;
+    Ok(new_instance(object, f(data, other)))
+}
+
+fn operand<R: FromValue>(value: Value) -> Result<R, Failure> {
+    R::from_value(value).ok_or_else(|| {
+        let message = format!(
+            "can't convert {} into {}",
+            class_name(value),
+            any::type_name::<R>()
+        );
+        Failure::new(unsafe { rb_eTypeError }, message)
+    })
+}
+
+fn new_instance<T: TypedData>(object: Value, data: T) -> Value {
+    wrap_typed_value(unsafe { rb_obj_class(object) }, data)
+}
@@ -0,0 +1,165 @@
+//! Wrapping data shared between Ruby and Rust.
+//!
+//! An `Rc<T>` or `Arc<T>` is wrapped like any other value; the Ruby object
+//! owns one strong reference, which is dropped when the object is freed (or
+//! the data removed). Rust code can hold on to more references in the
+//! meantime, and `get_rc`/`get_arc` hand out new ones without emptying the
+//! object.
+//!
+//! The `_unique` variants also remember which object they created for which
+//! data, so handing the same data to Ruby twice gives back the same object.
+//! The objects aren't kept alive by this; freeing one forgets it. They are
+//! pinned, though, so `GC.compact` can't move them away from the address
+//! remembered for them.
+
+use ruby_sys::types::{c_void, Value};
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use super::gc::rb_gc_mark;
+use super::unwind::abort_on_panic;
+use super::{drop_slot, free, new_slot, rb_data_object_wrap, with_ref, wrap_value};
+use super::{DataFunc, Slot, WrapError};
+
+struct Identities {
+    // object and slot, by the address of the data they share
+    objects: BTreeMap<usize, (usize, usize)>,
+    // the address each slot was registered under
+    addresses: BTreeMap<usize, usize>,
+}
+
+static IDENTITIES: Mutex<Identities> = Mutex::new(Identities {
+    objects: BTreeMap::new(),
+    addresses: BTreeMap::new(),
+});
+
+/// Creates a new instance of the given class, wrapping a strong reference
+/// to the given data.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+/// * `data`  - an `Rc<T>` - a reference to the data you wish to share with the Ruby object
+pub fn wrap_rc<T: 'static>(klass: Value, data: Rc<T>) -> Value {
+    wrap_value(klass, data)
+}
+
+/// Creates a new instance of the given class, wrapping a strong reference
+/// to the given data.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+/// * `data`  - an `Arc<T>` - a reference to the data you wish to share with the Ruby object
+pub fn wrap_arc<T: 'static>(klass: Value, data: Arc<T>) -> Value {
+    wrap_value(klass, data)
+}
+
+/// Like `wrap_rc`, but if `data` points to the same data as an `Rc` already
+/// wrapped this way, returns the existing Ruby object instead (whatever its
+/// class).
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+/// * `data`  - an `Rc<T>` - a reference to the data you wish to share with the Ruby object
+pub fn wrap_rc_unique<T: 'static>(klass: Value, data: Rc<T>) -> Value {
+    wrap_unique(klass, data, |data| Rc::as_ptr(data) as usize)
+}
+
+/// Like `wrap_arc`, but if `data` points to the same data as an `Arc`
+/// already wrapped this way, returns the existing Ruby object instead
+/// (whatever its class).
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+/// * `data`  - an `Arc<T>` - a reference to the data you wish to share with the Ruby object
+pub fn wrap_arc_unique<T: 'static>(klass: Value, data: Arc<T>) -> Value {
+    wrap_unique(klass, data, |data| Arc::as_ptr(data) as usize)
+}
+
+/// Returns a new strong reference to the data shared with the given Ruby
+/// object, leaving the object's own reference in place. Fails just like
+/// `get_ref::<Rc<T>>`.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created with `wrap_rc`
+pub fn get_rc<T: 'static>(object: Value) -> Result<Rc<T>, WrapError> {
+    with_ref(object, |data: &Rc<T>| data.clone())
+}
+
+/// Returns a new strong reference to the data shared with the given Ruby
+/// object, leaving the object's own reference in place. Fails just like
+/// `get_ref::<Arc<T>>`.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created with `wrap_arc`
+pub fn get_arc<T: 'static>(object: Value) -> Result<Arc<T>, WrapError> {
+    with_ref(object, |data: &Arc<T>| data.clone())
+}
+
+fn wrap_unique<P: 'static>(klass: Value, data: P, address: fn(&P) -> usize) -> Value {
+    let key = address(&data);
+    let existing = IDENTITIES.lock().unwrap().objects.get(&key).cloned();
+    if let Some((object, _)) = existing {
+        // the object may have been emptied or given other data since
+        let object = Value { value: object };
+        if with_ref(object, |held: &P| address(held) == key) == Ok(true) {
+            return object;
+        }
+    }
+
+    // Ruby may collect garbage (and so call `forget`) while allocating, so
+    // the lock can't be held here
+    let slot = new_slot(Some(data), drop_unique_slot::<P>);
+    let object = unsafe { rb_data_object_wrap(klass, slot, Some(pin), Some(free)) };
+    let mut identities = IDENTITIES.lock().unwrap();
+    identities
+        .objects
+        .insert(key, (object.value, slot as usize));
+    identities.addresses.insert(slot as usize, key);
+    object
+}
+
+unsafe fn drop_unique_slot<P>(slot: *mut Slot<P>) {
+    forget(slot as usize);
+    drop_slot::<P>(slot);
+}
+
+// The mark function of the objects `wrap_unique` creates. Rather than
+// anything the data holds, it marks the object itself, which pins it.
+extern "C" fn pin(slot: *mut c_void) {
+    abort_on_panic("mark", || {
+        let slot = slot as usize;
+        let object = {
+            let identities = IDENTITIES.lock().unwrap();
+            let key = identities.addresses.get(&slot);
+            match key.and_then(|key| identities.objects.get(key)) {
+                Some(&(object, owner)) if owner == slot => object,
+                _ => return,
+            }
+        };
+        unsafe { rb_gc_mark(Value { value: object }) };
+    });
+}
+
+// Whether `dmark` is `pin`, which leaves the data alone.
+pub(crate) fn is_pin(dmark: DataFunc) -> bool {
+    let pin: extern "C" fn(*mut c_void) = pin;
+    dmark.map(|f| f as usize) == Some(pin as usize)
+}
+
+fn forget(slot: usize) {
+    let mut identities = IDENTITIES.lock().unwrap();
+    if let Some(key) = identities.addresses.remove(&slot) {
+        // unless a newer object has taken over the address
+        if identities.objects.get(&key).map(|&(_, owner)| owner) == Some(slot) {
+            identities.objects.remove(&key);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! Typed data support, i.e. what Ruby's `TypedData_Wrap_Struct` macro does.
+//!
+//! Every wrapped Rust type gets its own `rb_data_type_t` descriptor, which
+//! Ruby uses to tell wrapped types apart, both in Rust (`remove_typed` and
+//! `set_typed` check it) and in C extensions using `TypedData_Get_Struct`.
+
+use ruby_sys::types::{c_void, Value};
+
+use std::marker::PhantomData;
+#[cfg(feature = "embed")]
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+use super::DropSlot;
+use super::{background, drop_slot, free, gc, memsize, new_slot, rdata, remove, set};
+#[cfg(feature = "compact")]
+use super::{compact, Compact};
+#[cfg(feature = "embed")]
+use super::{data_ptr, drop_slot_in_place, make_slot, Slot};
+use super::{DataFunc, Mark, MemSize, WrapError};
+
+extern "C" {
+    fn rb_data_typed_object_wrap(
+        klass: Value,
+        datap: *mut c_void,
+        data_type: *const c_void,
+    ) -> Value;
+    fn rb_typeddata_is_kind_of(object: Value, data_type: *const c_void) -> c_int;
+    #[cfg(feature = "embed")]
+    fn rb_data_typed_object_zalloc(klass: Value, size: usize, data_type: *const c_void) -> Value;
+}
+
+// `RUBY_TYPED_FREE_IMMEDIATELY`: free the wrapped data as soon as the
+// object is swept, rather than deferring it to Ruby's finalizer phase.
+pub(crate) const FREE_IMMEDIATELY: usize = 1;
+
+// `RUBY_TYPED_EMBEDDABLE`: keep the data inside the object itself when it
+// fits, rather than in memory of its own.
+#[cfg(feature = "embed")]
+const EMBEDDABLE: usize = 2;
+
+#[repr(C)]
+struct DataTypeFunctions {
+    dmark: Option<extern "C" fn(*mut c_void)>,
+    dfree: Option<extern "C" fn(*mut c_void)>,
+    dsize: Option<extern "C" fn(*const c_void) -> usize>,
+    // reserved (and required to be NULL) before Ruby 2.7
+    dcompact: Option<extern "C" fn(*mut c_void)>,
+    reserved: [*mut c_void; 1],
+}
+
+/// A Ruby `rb_data_type_t` describing how to wrap values of type `T`.
+///
+/// Ruby compares data types by address, so a `DataType` must live in a
+/// `static` and be handed out through `TypedData::data_type`:
+///
+/// ```rust,ignore
+/// static MY_VALUE_TYPE: DataType<MyValue> = DataType::new("MyValue\0");
+///
+/// impl TypedData for MyValue {
+///     fn data_type() -> &'static DataType<MyValue> {
+///         &MY_VALUE_TYPE
+///     }
+/// }
+/// ```
+#[repr(C)]
+pub struct DataType<T> {
+    wrap_struct_name: *const c_char,
+    function: DataTypeFunctions,
+    parent: *const c_void,
+    data: *mut c_void,
+    flags: usize,
+    // Ruby's struct ends here
+    drop: DropSlot<T>,
+    // how to drop the slot of an embedded object, whose memory Ruby frees
+    #[cfg(feature = "embed")]
+    drop_in_place: DropSlot<T>,
+    marker: PhantomData<fn() -> T>,
+}
+
+// The descriptor is never mutated after construction, and Ruby only ever
+// reads it, so sharing it between threads is fine.
+unsafe impl<T> Sync for DataType<T> {}
+
+impl<T> DataType<T> {
+    /// Builds a data type descriptor for `T`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - the name Ruby uses for this type in error messages and
+    ///   `ObjectSpace` dumps; it must end with a `\0`
+    pub const fn new(name: &'static str) -> DataType<T> {
+        let bytes = name.as_bytes();
+        assert!(
+            !bytes.is_empty() && bytes[bytes.len() - 1] == 0,
+            "DataType name must be NUL-terminated"
+        );
+        DataType {
+            wrap_struct_name: bytes.as_ptr() as *const c_char,
+            function: DataTypeFunctions {
+                dmark: None,
+                dfree: Some(free),
+                dsize: Some(memsize::size::<T>),
+                dcompact: None,
+                reserved: [ptr::null_mut(); 1],
+            },
+            parent: ptr::null(),
+            data: ptr::null_mut(),
+            flags: 0,
+            drop: drop_slot::<T>,
+            #[cfg(feature = "embed")]
+            drop_in_place: drop_slot_in_place::<T>,
+            marker: PhantomData,
+        }
+    }
+
+    /// Declares `parent` as the parent of this type, so objects of this type
+    /// are also accepted wherever Ruby expects the parent type. From Rust,
+    /// the data can still only be read as exactly `T`.
+    pub const fn parent<P>(mut self, parent: &'static DataType<P>) -> DataType<T> {
+        self.parent = parent as *const DataType<P> as *const c_void;
+        self
+    }
+
+    /// Has Ruby drop the wrapped data while sweeping, as soon as the object
+    /// is found to be garbage.
+    ///
+    /// By default, the drop is deferred to Ruby's finalizer phase, which runs
+    /// after the GC is done. That is the right choice for types whose `Drop`
+    /// does real work (closing sockets, flushing files, taking locks); this
+    /// is cheaper for types whose `Drop` only frees memory.
+    pub const fn free_immediately(mut self) -> DataType<T> {
+        self.flags |= FREE_IMMEDIATELY;
+        self
+    }
+
+    /// Has Ruby keep the wrapped data inside the object itself, saving an
+    /// allocation per object, where it fits (Ruby falls back to memory of
+    /// its own otherwise). Objects are then freed like `free_immediately`,
+    /// which Ruby requires of embedded data.
+    ///
+    /// Needs the `embed` feature, and Ruby 3.3 or newer.
+    #[cfg(feature = "embed")]
+    pub const fn embeddable(mut self) -> DataType<T> {
+        // Ruby only aligns objects to pointers
+        assert!(
+            mem::align_of::<Slot<T>>() <= mem::align_of::<usize>(),
+            "embedded data can only be aligned to pointers"
+        );
+        self.flags |= FREE_IMMEDIATELY | EMBEDDABLE;
+        self
+    }
+
+    fn as_ptr(&'static self) -> *const c_void {
+        self as *const DataType<T> as *const c_void
+    }
+}
+
+impl<T: Mark> DataType<T> {
+    /// Has Ruby call `T::mark` during the GC mark phase, keeping alive any
+    /// Ruby values the wrapped data holds.
+    pub const fn mark(mut self) -> DataType<T> {
+        self.function.dmark = Some(gc::mark::<T>);
+        self
+    }
+}
+
+#[cfg(feature = "compact")]
+impl<T: Compact> DataType<T> {
+    /// Marks the wrapped data's Ruby values as movable and has Ruby call
+    /// `T::compact` after `GC.compact` so they can be updated. Use this
+    /// instead of `mark`.
+    pub const fn compact(mut self) -> DataType<T> {
+        self.function.dmark = Some(compact::mark_movable::<T>);
+        self.function.dcompact = Some(compact::compact::<T>);
+        self
+    }
+}
+
+impl<T: Send + 'static> DataType<T> {
+    /// Hands the wrapped data to a background thread to be dropped, rather
+    /// than dropping it during GC. Worth it for types that take a long time
+    /// to drop, like huge maps or trees. See `flush_background_drops`.
+    pub const fn drop_in_background(mut self) -> DataType<T> {
+        self.drop = background::drop_slot::<T>;
+        #[cfg(feature = "embed")]
+        {
+            self.drop_in_place = background::drop_slot_in_place::<T>;
+        }
+        self
+    }
+}
+
+impl<T: MemSize> DataType<T> {
+    /// Reports `T::memsize` to Ruby (e.g. for `ObjectSpace.memsize_of`)
+    /// rather than just the inline size of `T`.
+    pub const fn memsize(mut self) -> DataType<T> {
+        self.function.dsize = Some(memsize::memsize::<T>);
+        self
+    }
+}
+
+/// Implemented by types that can be wrapped with `wrap_typed`.
+pub trait TypedData: Sized + 'static {
+    /// Returns the one and only data type descriptor for this type.
+    fn data_type() -> &'static DataType<Self>;
+}
+
+/// Creates a new instance of the given class, wrapping the given
+/// heap-allocated data type and tagging it with `T`'s data type.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+/// * `data`  - an `Option<Box<T>>` - the data you wish to embed in the Ruby object or None
+pub fn wrap_typed<T: TypedData>(klass: Value, data: Option<Box<T>>) -> Value {
+    new_typed(klass, data.map(|data| *data))
+}
+
+/// Like `wrap_typed`, but takes the data itself rather than a box, saving an
+/// allocation.
+///
+/// # Arguments
+///
+/// * `klass` - a Ruby Class
+/// * `data`  - a `T` - the data you wish to embed in the Ruby object
+pub fn wrap_typed_value<T: TypedData>(klass: Value, data: T) -> Value {
+    new_typed(klass, Some(data))
+}
+
+/// Removes and returns the wrapped data from the given Ruby object, leaving
+/// it empty. Fails just like `remove`, and also with
+/// `WrapError::TypeMismatch` if Ruby doesn't consider the object to be of
+/// `T`'s data type.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created with `wrap_typed`
+pub fn remove_typed<T: TypedData>(object: Value) -> Result<Box<T>, WrapError> {
+    check_type::<T>(object)?;
+    remove(object)
+}
+
+/// Sets the wrapped data on the given Ruby object, dropping any data it
+/// already held. Fails just like `set`, and also with
+/// `WrapError::TypeMismatch` if Ruby doesn't consider the object to be of
+/// `T`'s data type.
+///
+/// # Arguments
+///
+/// * `object` - a Ruby object created with `wrap_typed`
+/// * `data`   - a `Box<T>` - the data you wish to embed in the Ruby object
+pub fn set_typed<T: TypedData>(object: Value, data: Box<T>) -> Result<(), WrapError> {
+    check_type::<T>(object)?;
+    set(object, data)
+}
+
+fn new_typed<T: TypedData>(klass: Value, data: Option<T>) -> Value {
+    let data_type = T::data_type();
+    #[cfg(feature = "embed")]
+    {
+        if data_type.flags & EMBEDDABLE != 0 {
+            return embed(klass, data, data_type);
+        }
+    }
+    let datap = new_slot(data, data_type.drop);
+    unsafe { rb_data_typed_object_wrap(klass, datap, data_type.as_ptr()) }
+}
+
+// Has Ruby allocate room for the slot, inside the object if it fits, and
+// moves the slot there.
+#[cfg(feature = "embed")]
+fn embed<T: TypedData>(klass: Value, data: Option<T>, data_type: &'static DataType<T>) -> Value {
+    let slot = make_slot(data, data_type.drop_in_place);
+    unsafe {
+        let object =
+            rb_data_typed_object_zalloc(klass, mem::size_of::<Slot<T>>(), data_type.as_ptr());
+        ptr::write(data_ptr(object.value as *const _) as *mut Slot<T>, slot);
+        object
+    }
+}
+
+// Returns the mark and free functions of the given `rb_data_type_t`, whoever
+// made it.
+pub(crate) unsafe fn data_funcs(data_type: *const c_void) -> (DataFunc, DataFunc) {
+    let function = &(*(data_type as *const DataType<()>)).function;
+    (function.dmark, function.dfree)
+}
+
+// Ruby's own check, which also accepts children of `T`'s data type, made
+// after checking the object is one of ours at all. The slot is checked for
+// exactly `T` afterwards, so a child can't be read as its parent.
+fn check_type<T: TypedData>(object: Value) -> Result<(), WrapError> {
+    rdata(object)?;
+    if unsafe { rb_typeddata_is_kind_of(object, T::data_type().as_ptr()) } == 0 {
+        Err(WrapError::TypeMismatch)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! Keeping Rust panics from unwinding into Ruby.
+//!
+//! Unwinding through C frames is undefined behaviour, so every callback this
+//! crate hands to Ruby catches panics before they escape. Callbacks that run
+//! during GC (free, mark, compact, memsize) or while interrupting another
+//! thread (`without_gvl_unblock`) have no sensible way to report a failure,
+//! so a panic there aborts the process. Everywhere else, the panic is turned
+//! into a Ruby `RuntimeError`.
+//!
+//! The other way round, Ruby exceptions unwind with `longjmp`, skipping any
+//! Rust destructors on the way. Code that must clean up after calling into
+//! Ruby catches them with `protect` and carries on with `jump_tag` after.
+
+use ruby_sys::types::Value;
+use ruby_sys::value::RubySpecialConsts::Nil;
+
+use std::any::Any;
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::thread;
+
+use super::error::{raise, rb_eRuntimeError};
+
+extern "C" {
+    fn rb_protect(func: extern "C" fn(Value) -> Value, arg: Value, state: *mut c_int) -> Value;
+    fn rb_jump_tag(state: c_int) -> !;
+}
+
+/// Calls `f`, turning any panic into a Ruby `RuntimeError` instead of letting
+/// it unwind into Ruby.
+///
+/// Wrap the body of every `extern "C"` function you hand to Ruby (e.g. with
+/// `rb_define_method`) in this. Don't raise Ruby exceptions inside `f`, as
+/// they would unwind straight past the frames catching the panic; note the
+/// failure and raise it once this returns:
+///
+/// ```rust,ignore
+/// extern "C" fn length(itself: Value) -> Value {
+///     let mut failure = None;
+///     let len = ruby_wrap_data::rescue_panic(|| {
+///         match ruby_wrap_data::with_ref(itself, |list: &List| list.len()) {
+///             Ok(len) => int2num(len),
+///             Err(error) => {
+///                 failure = Some(error);
+///                 RB_NIL
+///             }
+///         }
+///     });
+///     if let Some(error) = failure {
+///         error.raise();
+///     }
+///     len
+/// }
+/// ```
+pub fn rescue_panic<F: FnOnce() -> Value>(f: F) -> Value {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => value,
+        Err(cause) => raise_panic(cause),
+    }
+}
+
+// Runs a callback Ruby makes where it can't take an exception, e.g. while
+// collecting garbage.
+pub(crate) fn abort_on_panic<R, F: FnOnce() -> R>(callback: &str, f: F) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(cause) => {
+            eprintln!(
+                "ruby_wrap_data: panic in {} callback: {}",
+                callback,
+                panic_message(&*cause)
+            );
+            process::abort();
+        }
+    }
+}
+
+// Calls `f` under `rb_protect`, returning the tag of any Ruby exception (or
+// `break`, `throw`...) that escapes it, to be passed to `jump_tag` once the
+// caller has cleaned up. A panic in `f` is carried across Ruby's frames and
+// resumed here.
+pub(crate) fn protect<R, F: FnOnce() -> R>(f: F) -> Result<R, c_int> {
+    let mut call: (Option<F>, Option<thread::Result<R>>) = (Some(f), None);
+    let arg = Value {
+        value: &mut call as *mut (Option<F>, Option<thread::Result<R>>) as usize,
+    };
+    let mut state = 0;
+    unsafe { rb_protect(protected::<R, F>, arg, &mut state) };
+    if state != 0 {
+        return Err(state);
+    }
+    match call.1.take().unwrap() {
+        Ok(result) => Ok(result),
+        Err(cause) => panic::resume_unwind(cause),
+    }
+}
+
+extern "C" fn protected<R, F: FnOnce() -> R>(arg: Value) -> Value {
+    let call = unsafe { &mut *(arg.value as *mut (Option<F>, Option<thread::Result<R>>)) };
+    let f = call.0.take().unwrap();
+    call.1 = Some(panic::catch_unwind(AssertUnwindSafe(f)));
+    Value {
+        value: Nil as usize,
+    }
+}
+
+// Carries on with whatever `protect` caught.
+pub(crate) fn jump_tag(state: c_int) -> ! {
+    unsafe { rb_jump_tag(state) }
+}
+
+fn raise_panic(cause: Box<dyn Any + Send>) -> ! {
+    let message = format!("panic in Rust code: {}", panic_message(&*cause));
+    drop(cause);
+    raise(unsafe { rb_eRuntimeError }, message)
+}
+
+fn panic_message(cause: &(dyn Any + Send)) -> &str {
+    if let Some(message) = cause.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = cause.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<Any>"
+    }
+}